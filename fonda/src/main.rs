@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
 use tokio::process::Command as TokioCommand;
 use std::env::consts::OS;
 use std::time::Instant;
@@ -9,38 +11,304 @@ use std::time::Instant;
 const REQUIREMENTS_FILE: &str = "requirements.txt";
 const ENVIRONMENT_FILE: &str = "environment.yaml";
 const PYTHON_COMMANDS: [&str; 3] = ["python", "python3", "py"];
-const DEBUG_FILE: &str = "fonda_debug.log";
-static mut VERBOSE_MODE: bool = false;
+const ENVS_DIR_VAR: &str = "FONDA_ENVS_DIR";
+const REGISTRY_FILE: &str = "registry.json";
+/// A project's log file is rotated once it grows past this size
+const LOG_MAX_BYTES: u64 = 1024 * 1024;
+/// Rotated log files kept per project (`<name>.log.1` to `<name>.log.3`)
+const LOG_KEEP: usize = 3;
+const WHEELHOUSE_DIR: &str = "wheels";
+const PYPI_INDEX: &str = "https://pypi.org/simple";
+/// Package indexes for well-known channels; others are mapped with `channel_indexes:`
+const CHANNEL_INDEXES: [(&str, &str); 2] = [
+    ("pypi", PYPI_INDEX),
+    ("pytorch", "https://download.pytorch.org/whl/cpu"),
+];
+/// Flags that take a value, e.g. `fonda --log-file fonda.log -w`. As in
+/// `collect_flag_values`, the next argument is only a value if it does not start with `-`.
+const VALUE_FLAGS: [&str; 9] = [
+    "-f", "-r", "--env", "--prefix", "--with", "--without", "--wheelhouse", "--platform", "--log-file",
+];
+/// Printed when the first word after the flags is not a subcommand, e.g. `fonda -v lst`
+const COMMAND_USAGE: &str =
+    "Usage: fonda [options] [create|update|check|status|why|download|shell|run|env|hook], or fonda [options] -w|-r [file]";
+/// The `--json` document, built up while a command runs (`None` without `--json`)
+static JSON_REPORT: Mutex<Option<JsonReport>> = Mutex::new(None);
+/// Where log events go; configured once from the global flags in `run_cli`
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    console_level: LogLevel::Info,
+    file_level: LogLevel::Debug,
+    file: None,
+    file_path: None,
+});
 
-/// Print debug information if verbose mode is enabled
+/// Log a warning: on stderr (unless more than quiet) and in the log file
+macro_rules! warn_println {
+    ($($arg:tt)*) => {
+        log_event(LogLevel::Warn, &format!($($arg)*))
+    };
+}
+
+/// Log progress: shown unless `-q` is given
+macro_rules! info_println {
+    ($($arg:tt)*) => {
+        log_event(LogLevel::Info, &format!($($arg)*))
+    };
+}
+
+/// Log debug information: shown with `-v`, always written to the log file
 macro_rules! debug_println {
     ($($arg:tt)*) => {
-        if unsafe { VERBOSE_MODE } {
+        log_event(LogLevel::Debug, &format!($($arg)*))
+    };
+}
+
+/// Log step-by-step detail: shown and written to the log file with `-vv`
+macro_rules! trace_println {
+    ($($arg:tt)*) => {
+        log_event(LogLevel::Trace, &format!($($arg)*))
+    };
+}
+
+/// Print output for people: to stdout, or to stderr with `--json` so that
+/// stdout only carries the JSON document
+macro_rules! status_println {
+    ($($arg:tt)*) => {
+        if json_mode() {
+            eprintln!($($arg)*);
+        } else {
             println!($($arg)*);
         }
     };
 }
 
+/// How much detail a log event carries, from most to least important
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+/// The console and log file levels; an event is written wherever its level is enabled
+struct Logger {
+    /// `-q`: Warn, default: Info, `-v`: Debug, `-vv`: Trace
+    console_level: LogLevel,
+    /// Debug, or Trace with `-vv`
+    file_level: LogLevel,
+    /// `None` with `--no-log-file` or when the log file could not be opened
+    file: Option<File>,
+    /// Where `file` is, named in error hints
+    file_path: Option<PathBuf>,
+}
+
+/// Sends one event to the console and the log file, each at its own level
+fn log_event(level: LogLevel, message: &str) {
+    let mut logger = LOGGER.lock().unwrap_or_else(PoisonError::into_inner);
+    if level <= logger.console_level {
+        match level {
+            LogLevel::Error => eprintln!("Error: {}", message),
+            LogLevel::Warn => eprintln!("Warning: {}", message),
+            _ => status_println!("{}", message),
+        }
+    }
+    if level <= logger.file_level {
+        if let Some(file) = logger.file.as_mut() {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            // A failing log file must never fail the command
+            let _ = writeln!(file, "{} {:<5} {}", format_timestamp(now), level.label(), message);
+        }
+    }
+}
+
+/// The document `--json` prints on stdout when the command finishes
+#[derive(Serialize, Default)]
+struct JsonReport {
+    command: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    environment: Option<JsonEnvironment>,
+    /// requirements.txt as written (or, for `status`, as compared)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requirements_file: Option<String>,
+    /// `status`: whether requirements.txt matches the YAML (up_to_date, out_of_date or missing)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requirements_status: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    requirements: Vec<JsonRequirement>,
+    /// `--dry-run`: nothing was run or written
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    /// External commands in the order they ran
+    commands: Vec<JsonCommand>,
+    /// `--dry-run`: the commands that would run, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    planned_commands: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<JsonError>,
+}
+
+#[derive(Serialize, Default)]
+struct JsonEnvironment {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    /// What created the virtual environment: uv or venv
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exists: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    python_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registered: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct JsonRequirement {
+    spec: String,
+    source: String,
+    line: usize,
+    included: bool,
+    reason: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    overrides: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct JsonCommand {
+    command: String,
+    duration_ms: u64,
+    /// `None` when the command could not be started or was killed by a signal
+    exit_code: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    kind: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+    exit_code: i32,
+}
+
+fn json_mode() -> bool {
+    JSON_REPORT.lock().unwrap_or_else(PoisonError::into_inner).is_some()
+}
+
+/// Updates the `--json` report; does nothing without `--json`
+fn json_record(update: impl FnOnce(&mut JsonReport)) {
+    if let Some(report) = JSON_REPORT.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+        update(report);
+    }
+}
+
+/// Updates the environment part of the `--json` report
+fn json_record_environment(update: impl FnOnce(&mut JsonEnvironment)) {
+    json_record(|report| update(report.environment.get_or_insert_with(JsonEnvironment::default)));
+}
+
+/// Adds the included and skipped requirements to the `--json` report
+fn json_record_requirements(requirements: &Requirements) {
+    json_record(|report| {
+        let included = requirements.included.iter().map(|requirement| (requirement, true));
+        let skipped = requirements.skipped.iter().map(|requirement| (requirement, false));
+        report.requirements = included.chain(skipped)
+            .map(|(requirement, included)| JsonRequirement {
+                spec: requirement.spec.clone(),
+                source: display_source(&requirement.source),
+                line: requirement.line,
+                included,
+                reason: requirement.reason.clone(),
+                overrides: requirement.overrides.clone(),
+            })
+            .collect();
+    });
+}
+
+/// Prints the `--json` report on stdout, if `--json` was given
+fn print_json_report() {
+    if let Some(report) = JSON_REPORT.lock().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        match serde_json::to_string_pretty(report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Error: could not serialize the JSON report: {}", e),
+        }
+    }
+}
+
+/// Reports a command-line usage error (as JSON with `--json`) and exits with code 1
+fn exit_with_usage(message: &str, usage: &str) -> ! {
+    eprintln!("Error: {}", message);
+    eprintln!("{}", usage);
+    json_record(|report| report.error = Some(JsonError {
+        kind: "usage",
+        message: message.to_string(),
+        hint: Some(usage.to_string()),
+        exit_code: 1,
+    }));
+    print_json_report();
+    std::process::exit(1);
+}
+
 #[derive(Debug)]
 enum FondaError {
-    Io(io::Error),
+    Io { context: String, error: io::Error },
     Yaml(serde_yaml::Error),
     PythonNotFound(String),
     VenvCreationFailed(String),
     EnvironmentExists(String),
+    EnvironmentNotFound(String),
+    InvalidConfig(String),
+    IncludeCycle(String),
+    UndefinedVariable(String),
+    OfflineInstallFailed(String),
+    ToolNotInstalled(String),
+    DependencyConflict(String),
     ConfigNotFound(String),
     RequirementsNotFound(String),
     CommandFailed { command: String, error: String },
 }
 
-impl From<io::Error> for FondaError {
-    fn from(err: io::Error) -> FondaError {
-        match err.kind() {
-            io::ErrorKind::NotFound => {
-                FondaError::ConfigNotFound(err.to_string())
-            }
-            _ => FondaError::Io(err)
-        }
+/// Attaches what was being done, and to which path, to an `io::Error`
+trait IoContext<T> {
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T, FondaError>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T, FondaError> {
+        self.map_err(|error| FondaError::Io { context: context(), error })
+    }
+}
+
+/// The error for a command that could not be started
+///
+/// A missing executable is reported as `FondaError::ToolNotInstalled`, so callers
+/// can tell "not installed" apart from "ran and failed".
+fn spawn_error(command: &str, args: &[&str], error: io::Error) -> FondaError {
+    if error.kind() == io::ErrorKind::NotFound {
+        return FondaError::ToolNotInstalled(command.to_string());
+    }
+    FondaError::CommandFailed {
+        command: format!("{} {}", command, args.join(" ")).trim_end().to_string(),
+        error: error.to_string(),
     }
 }
 
@@ -53,11 +321,18 @@ impl From<serde_yaml::Error> for FondaError {
 impl std::fmt::Display for FondaError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Io(err) => write!(f, "IO error: {}", err),
+            Self::Io { context, error } => write!(f, "{}: {}", context, error),
             Self::Yaml(err) => write!(f, "YAML parsing error: {}", err),
             Self::PythonNotFound(msg) => write!(f, "Python not found: {}", msg),
             Self::VenvCreationFailed(msg) => write!(f, "Failed to create virtual environment: {}", msg),
             Self::EnvironmentExists(name) => write!(f, "Environment already exists: {}", name),
+            Self::EnvironmentNotFound(msg) => write!(f, "Environment not found: {}", msg),
+            Self::InvalidConfig(msg) => write!(f, "Invalid configuration: {}", msg),
+            Self::IncludeCycle(cycle) => write!(f, "Include cycle: {}", cycle),
+            Self::UndefinedVariable(msg) => write!(f, "Undefined variable: {}", msg),
+            Self::OfflineInstallFailed(msg) => write!(f, "Offline install failed: {}", msg),
+            Self::ToolNotInstalled(tool) => write!(f, "Tool not installed: {} was not found on PATH", tool),
+            Self::DependencyConflict(msg) => write!(f, "Conflicting dependencies: {}", msg),
             Self::ConfigNotFound(msg) => write!(f, "Configuration file not found: {}", msg),
             Self::RequirementsNotFound(msg) => write!(f, "Requirements file not found: {}", msg),
             Self::CommandFailed { command, error } => write!(f, "Command '{}' failed: {}", command, error),
@@ -65,10 +340,82 @@ impl std::fmt::Display for FondaError {
     }
 }
 
+impl FondaError {
+    /// The process exit code for this error, documented in the README
+    ///
+    /// Usage errors exit with 1 before any `FondaError` is created.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io { .. } => 10,
+            Self::Yaml(_) => 11,
+            Self::ConfigNotFound(_) => 12,
+            Self::InvalidConfig(_) => 13,
+            Self::IncludeCycle(_) => 14,
+            Self::UndefinedVariable(_) => 15,
+            Self::DependencyConflict(_) => 16,
+            Self::RequirementsNotFound(_) => 17,
+            Self::PythonNotFound(_) => 20,
+            Self::VenvCreationFailed(_) => 21,
+            Self::EnvironmentExists(_) => 22,
+            Self::EnvironmentNotFound(_) => 23,
+            Self::OfflineInstallFailed(_) => 24,
+            Self::CommandFailed { .. } => 25,
+            Self::ToolNotInstalled(_) => 26,
+        }
+    }
+
+    /// Stable name of the error for `--json` output
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Io { .. } => "io",
+            Self::Yaml(_) => "yaml",
+            Self::ConfigNotFound(_) => "config_not_found",
+            Self::InvalidConfig(_) => "invalid_config",
+            Self::IncludeCycle(_) => "include_cycle",
+            Self::UndefinedVariable(_) => "undefined_variable",
+            Self::DependencyConflict(_) => "dependency_conflict",
+            Self::RequirementsNotFound(_) => "requirements_not_found",
+            Self::PythonNotFound(_) => "python_not_found",
+            Self::VenvCreationFailed(_) => "venv_creation_failed",
+            Self::EnvironmentExists(_) => "environment_exists",
+            Self::EnvironmentNotFound(_) => "environment_not_found",
+            Self::OfflineInstallFailed(_) => "offline_install_failed",
+            Self::CommandFailed { .. } => "command_failed",
+            Self::ToolNotInstalled(_) => "tool_not_installed",
+        }
+    }
+
+    /// A suggestion for fixing the error, printed after the message
+    fn hint(&self) -> Option<String> {
+        let hint = match self {
+            Self::Io { .. } | Self::UndefinedVariable(_) | Self::OfflineInstallFailed(_) => None,
+            Self::Yaml(_) => Some("check the YAML syntax; `fonda check` shows how the file is read"),
+            Self::ConfigNotFound(_) => Some("create environment.yaml, or pass another file with -f <file>"),
+            Self::InvalidConfig(_) => Some("run `fonda check` to validate the environment file"),
+            Self::IncludeCycle(_) => Some("remove one of the include: entries in the cycle"),
+            Self::DependencyConflict(_) => Some("make the specs agree, or use include: so that one file overrides the other"),
+            Self::RequirementsNotFound(_) => Some("generate requirements.txt with `fonda -w`, or pass files with -r <file>"),
+            Self::PythonNotFound(_) => Some("install Python 3 and make sure it is on PATH"),
+            Self::VenvCreationFailed(_) => Some("make sure the Python venv module is installed (python3-venv on Debian and Ubuntu)"),
+            Self::EnvironmentExists(_) => Some("run `fonda update` to install changes into it, or add --force to recreate it"),
+            Self::EnvironmentNotFound(_) => Some("create it with `fonda create`; `fonda env list` shows the environments fonda knows"),
+            Self::CommandFailed { .. } => {
+                let log_path = LOGGER.lock().unwrap_or_else(PoisonError::into_inner).file_path.clone();
+                return Some(match log_path {
+                    Some(path) => format!("run again with -v for details; every command is logged to {}", path.display()),
+                    None => "run again with -v for details, or with --log-file <file> to keep a log of every command".to_string(),
+                });
+            }
+            Self::ToolNotInstalled(_) => Some("install it, or check that the directory containing it is on PATH"),
+        };
+        hint.map(str::to_string)
+    }
+}
+
 impl std::error::Error for FondaError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Self::Io(err) => Some(err),
+            Self::Io { error, .. } => Some(error),
             Self::Yaml(err) => Some(err),
             _ => None,
         }
@@ -76,236 +423,1653 @@ impl std::error::Error for FondaError {
 }
 
 /// Configuration for a conda-style environment
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 struct CondaEnv {
-    /// Name of the environment
+    /// Name of the environment (may be left to an including file)
+    #[serde(default)]
     name: String,
+    /// Environment files to merge in before this one (optional, alias `extends`)
+    #[serde(default, alias = "extends")]
+    include: Option<OneOrMany>,
     /// Python version requirement (optional)
     #[serde(default)]
     python_version: Option<String>,
     /// List of conda channels to use (optional)
     #[serde(default)]
     channels: Option<Vec<String>>,
+    /// Package index URLs for channel names, e.g. an internal devpi (optional)
+    #[serde(default)]
+    channel_indexes: Option<BTreeMap<String, String>>,
     /// List of dependencies to install
+    #[serde(default)]
     dependencies: Vec<String>,
     /// List of pip packages to install (optional)
     #[serde(default)]
-    pip: Option<Vec<String>>,
+    pip: Option<Vec<PipEntry>>,
+    /// Main package index, replacing PyPI (optional)
+    #[serde(default)]
+    index_url: Option<String>,
+    /// Directories or pages to look for wheels in (optional)
+    #[serde(default)]
+    find_links: Option<OneOrMany>,
+    /// Hosts pip may reach over plain HTTP or without a valid certificate (optional)
+    #[serde(default)]
+    trusted_host: Option<OneOrMany>,
+    /// Directory to create the virtual environment in (optional)
+    #[serde(default)]
+    prefix: Option<String>,
+    /// Environment variables exported on activation (optional)
+    #[serde(default)]
+    variables: Option<BTreeMap<String, serde_yaml::Value>>,
+    /// Commands run inside the environment after it is created (optional)
+    #[serde(default)]
+    post_create: Option<Vec<HookCommand>>,
+    /// Commands run inside the environment after `fonda update` (optional)
+    #[serde(default)]
+    post_update: Option<Vec<HookCommand>>,
+    /// Register the environment as a Jupyter kernel (optional)
+    #[serde(default)]
+    jupyter_kernel: Option<bool>,
+    /// Named groups of extra dependencies, e.g. dev, test, docs (optional)
+    #[serde(default)]
+    groups: Option<BTreeMap<String, Vec<String>>>,
+}
+
+/// A field that takes one string or a list, such as `include:` or `find_links:`
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn items(&self) -> &[String] {
+        match self {
+            Self::One(item) => std::slice::from_ref(item),
+            Self::Many(items) => items,
+        }
+    }
+}
+
+/// A `pip:` entry: a requirement spec, or a mapping naming the index the package comes from
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+enum PipEntry {
+    Spec(String),
+    Package {
+        name: String,
+        #[serde(default)]
+        index: Option<String>,
+    },
+}
+
+impl PipEntry {
+    fn spec_mut(&mut self) -> &mut String {
+        match self {
+            Self::Spec(spec) => spec,
+            Self::Package { name, .. } => name,
+        }
+    }
+}
+
+/// A `post_create:` or `post_update:` entry: a plain command, or a mapping that
+/// can mark the command as optional
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum HookCommand {
+    Command(String),
+    Detailed {
+        run: String,
+        #[serde(default = "default_true")]
+        required: bool,
+    },
+}
+
+impl HookCommand {
+    fn command(&self) -> &str {
+        match self {
+            Self::Command(command) | Self::Detailed { run: command, .. } => command,
+        }
+    }
+
+    fn required(&self) -> bool {
+        match self {
+            Self::Command(_) => true,
+            Self::Detailed { required, .. } => *required,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Options shared by the commands, parsed from flags
+#[derive(Debug, Default)]
+struct FondaOptions {
+    /// Explicit virtual environment directory for -r (--env)
+    env_dir: Option<String>,
+    /// Allow -r to install into the system Python (--system)
+    system: bool,
+    /// Virtual environment location overriding the YAML and FONDA_ENVS_DIR (--prefix)
+    prefix: Option<String>,
+    /// Register created environments as Jupyter kernels (--jupyter-kernel)
+    jupyter_kernel: bool,
+    /// Only install these dependency groups (--with dev,test)
+    with_groups: Option<Vec<String>>,
+    /// Never install these dependency groups (--without docs)
+    without_groups: Vec<String>,
+    /// Install only from the wheelhouse, without any package index (--offline)
+    offline: bool,
+    /// Directory of downloaded wheels (--wheelhouse, default ./wheels)
+    wheelhouse: Option<String>,
+    /// Replace an existing environment when creating (--force)
+    force: bool,
+    /// Print what create, update or -r would do without doing it (--dry-run)
+    dry_run: bool,
+    /// Evaluate platform markers for this OS instead of the current one (--platform)
+    platform: Option<String>,
 }
 
 #[derive(Debug)]
 enum FondaCommand {
-    RunRequirements,
+    RunRequirements(Vec<String>),
     WriteRequirements,
-    WriteRequirementsCustomFile(String),
+    WriteRequirementsCustomFile(Vec<String>),
     CreateAndRun,
-    CustomFile(String),
+    CustomFile(Vec<String>),
+    Env(EnvAction),
+    Run(Vec<String>),
+    Shell,
+    Hook(String),
+    Update,
+    Check,
+    Download,
+    Status,
+    Why(String),
+}
+
+impl FondaCommand {
+    /// The command name in `--json` output, or `None` for commands without JSON output
+    fn json_name(&self) -> Option<&'static str> {
+        match self {
+            Self::CreateAndRun | Self::CustomFile(_) => Some("create"),
+            Self::WriteRequirements | Self::WriteRequirementsCustomFile(_) => Some("write"),
+            Self::RunRequirements(_) => Some("run-requirements"),
+            Self::Check => Some("check"),
+            Self::Status => Some("status"),
+            Self::Update => Some("update"),
+            Self::Download => Some("download"),
+            Self::Env(_) | Self::Run(_) | Self::Shell | Self::Hook(_) | Self::Why(_) => None,
+        }
+    }
+}
+
+/// Actions of the `fonda env` subcommand
+#[derive(Debug)]
+enum EnvAction {
+    List,
+    Path,
+    Info(String),
+    Remove(String),
 }
 
 impl From<&str> for FondaCommand {
     fn from(s: &str) -> Self {
         match s {
-            "-r" => FondaCommand::RunRequirements,
+            "-r" => FondaCommand::RunRequirements(Vec::new()), // Will be populated with the file paths later
             "-w" => FondaCommand::WriteRequirements,
-            "-f" => FondaCommand::CustomFile(String::new()), // Will be populated with the file path later
+            "-f" => FondaCommand::CustomFile(Vec::new()), // Will be populated with the file paths later
             _ => FondaCommand::CreateAndRun,
         }
     }
 }
 
-fn log_debug(message: &str) -> io::Result<()> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(DEBUG_FILE)?;
-    
-    writeln!(file, "{}", message)
-}
+/// Configures the logger from `-q`, `-v`, `-vv`, `--log-file` and `--no-log-file`.
+///
+/// Without `--log-file` the log goes to the project's file in the user's state
+/// directory (see [`project_log_path`]), rotated once it grows past [`LOG_MAX_BYTES`].
+/// Nothing is written to the project directory unless `--log-file` points there.
+fn init_logging(args: &[String]) {
+    let has_flag = |flag: &str| args.iter().any(|arg| arg == flag);
+    let console_level = if has_flag("-vv") {
+        LogLevel::Trace
+    } else if has_flag("-v") {
+        LogLevel::Debug
+    } else if has_flag("-q") {
+        LogLevel::Warn
+    } else {
+        LogLevel::Info
+    };
+    let file_level = if has_flag("-vv") { LogLevel::Trace } else { LogLevel::Debug };
 
-// Helper function to ensure debug log is created and writable
-fn ensure_debug_log() -> io::Result<()> {
-    // Create the debug log file if it doesn't exist
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(DEBUG_FILE)?;
-    
-    // Close the file handle
-    drop(file);
-    
-    // Log initial message
-    log_debug(&format!("Debug log initialized. OS: {}", OS))
+    let file = if has_flag("--no-log-file") {
+        Ok(None)
+    } else {
+        match flag_value(args, "--log-file") {
+            Some(path) => OpenOptions::new().create(true).append(true).open(&path)
+                .map(|file| Some((file, PathBuf::from(path.as_str()))))
+                .with_context(|| format!("could not open log file {}", path)),
+            // A dry run writes nothing, so the project log is neither created nor rotated
+            None if has_flag("--dry-run") => Ok(None),
+            None => open_project_log().map(Some),
+        }
+    };
+
+    let mut logger = LOGGER.lock().unwrap_or_else(PoisonError::into_inner);
+    logger.console_level = console_level;
+    logger.file_level = file_level;
+    let open_error = match file {
+        Ok(file) => {
+            (logger.file, logger.file_path) = file.unzip();
+            None
+        }
+        Err(error) => Some(error),
+    };
+    drop(logger);
+
+    if let Some(e) = open_error {
+        warn_println!("{}", e);
+    }
+    debug_println!("Log initialized. OS: {}, console level: {}, file level: {}", OS, console_level.label(), file_level.label());
 }
 
 #[tokio::main]
-async fn main() -> Result<(), FondaError> {
-    let args: Vec<String> = std::env::args().collect();
-    
-    // Ensure debug log is created and writable
-    if let Err(e) = ensure_debug_log() {
-        eprintln!("Warning: Failed to create debug log: {}", e);
+async fn main() {
+    let result = run_cli().await;
+    if let Err(err) = &result {
+        log_event(LogLevel::Error, &err.to_string());
+        if let Some(hint) = err.hint() {
+            eprintln!("Hint: {}", hint);
+        }
+        debug_println!("Exiting with code {} ({})", err.exit_code(), err.kind());
     }
-    
-    // Check for verbose mode flag
-    if args.contains(&"-v".to_string()) {
-        unsafe { VERBOSE_MODE = true; }
-        println!("Verbose mode enabled");
+
+    json_record(|report| {
+        report.success = result.is_ok();
+        if let Err(err) = &result {
+            report.error = Some(JsonError {
+                kind: err.kind(),
+                message: err.to_string(),
+                hint: err.hint(),
+                exit_code: err.exit_code(),
+            });
+        }
+    });
+    print_json_report();
+
+    if let Err(err) = result {
+        std::process::exit(err.exit_code());
     }
+}
+
+async fn run_cli() -> Result<(), FondaError> {
+    let mut args: Vec<String> = std::env::args().collect();
+
+    // The command for `fonda run` is everything after `--` (or after `run` when there is no `--`),
+    // so that its flags are never mistaken for fonda's own
+    let passthrough = match args.iter().position(|arg| arg == "--") {
+        Some(index) => {
+            let rest = args.split_off(index + 1);
+            args.truncate(index);
+            rest
+        }
+        None => match command_word_index(&args) {
+            Some(index) if args[index] == "run" => args.split_off(index + 1),
+            _ => Vec::new(),
+        },
+    };
     
-    // Find the first non-verbose flag to determine the command
-    let command_arg = args.iter().skip(1)
-        .find(|&arg| arg != "-v")
-        .map(String::as_str)
-        .unwrap_or("");
-    
-    // Parse command and optional file path
-    let mut command = FondaCommand::from(command_arg);
+    if args.iter().any(|arg| arg == "--json") {
+        *JSON_REPORT.lock().unwrap_or_else(PoisonError::into_inner) = Some(JsonReport::default());
+    }
+
+    init_logging(&args);
     
-    // Check for -w -f combination
-    let w_index = args.iter().position(|arg| arg == "-w");
-    let f_index = args.iter().position(|arg| arg == "-f");
+    // The first word that is not a flag or a flag's value is the subcommand (e.g. `fonda
+    // --offline env list`); flags before and after it are options
+    let subcommand_args: Vec<&str> = command_word_index(&args)
+        .map(|index| args[index..].iter().map(String::as_str).collect())
+        .unwrap_or_default();
+    let mut command = match parse_subcommand(&subcommand_args) {
+        Some(result) => result.unwrap_or_else(|usage| {
+            exit_with_usage(&format!("invalid arguments for `fonda {}`", subcommand_args[0]), &usage)
+        }),
+        // Never fall back to create for a word fonda does not know
+        None if !subcommand_args.is_empty() => exit_with_usage(
+            &format!("unknown command '{}'", subcommand_args[0]),
+            COMMAND_USAGE,
+        ),
+        None => {
+            // Otherwise the first command flag decides; every other flag is an option
+            let command_arg = args.iter().skip(1)
+                .find(|&arg| arg == "-r" || arg == "-w")
+                .map(String::as_str)
+                .unwrap_or("");
+            FondaCommand::from(command_arg)
+        }
+    };
     
-    if let (Some(_w_index), Some(f_index)) = (w_index, f_index) {
-        // Get the file path after -f
-        if let Some(file_path) = args.get(f_index + 1) {
-            // Validate that the file exists and has a .yaml or .yml extension
-            let path = Path::new(file_path);
-            if !path.exists() {
-                eprintln!("Error: File not found: {}", file_path);
-                eprintln!("Usage: fonda -w -f <environment_file.yaml>");
-                std::process::exit(1);
-            }
-            
-            let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
-            if extension != "yaml" && extension != "yml" {
-                eprintln!("Warning: File does not have .yaml or .yml extension: {}", file_path);
-                let _ = log_debug(&format!("Warning: File does not have .yaml or .yml extension: {}", file_path));
-            }
-            
-            command = FondaCommand::WriteRequirementsCustomFile(file_path.clone());
-            let _ = log_debug(&format!("Using -w -f with file: {}", file_path));
-        } else {
-            eprintln!("Error: -w -f flags require a file path argument");
-            eprintln!("Usage: fonda -w -f <environment_file.yaml>");
-            std::process::exit(1);
+    if json_mode() {
+        match command.json_name() {
+            Some(name) => json_record(|report| report.command = name.to_string()),
+            None => exit_with_usage(
+                "--json is not supported by this command",
+                "--json works with create, -w, -r, check, status, update and download",
+            ),
+        }
+    }
+
+    if let FondaCommand::Run(ref mut child) = command {
+        *child = passthrough;
+        if child.is_empty() {
+            exit_with_usage(
+                "fonda run requires a command",
+                "Usage: fonda run [-f <environment_file.yaml>] [--prefix <dir>] -- <command> [args...]",
+            );
+        }
+    }
+
+    // Collect every requirements file passed with -r, defaulting to requirements.txt
+    if let FondaCommand::RunRequirements(ref mut files) = command {
+        *files = collect_flag_values(&args, "-r");
+        if files.is_empty() {
+            files.push(REQUIREMENTS_FILE.to_string());
         }
+        debug_println!("Using -r with files: {}", files.join(", "));
     }
-    // If using -f flag without -w, get the file path from the next argument
-    else if let (Some(f_index), None) = (f_index, w_index) {
-        if let Some(file_path) = args.get(f_index + 1) {
+
+    // Get the environment file paths after each -f, merged in the order given
+    let mut env_files = vec![ENVIRONMENT_FILE.to_string()];
+    let f_count = args.iter().filter(|&arg| arg == "-f").count();
+    if f_count > 0 {
+        let usage = match command {
+            FondaCommand::WriteRequirements => "Usage: fonda -w -f <environment_file.yaml> [-f <more.yaml>...]",
+            FondaCommand::RunRequirements(_) => "Usage: fonda -r [requirements.txt] -f <environment_file.yaml>",
+            _ => "Usage: fonda -f <environment_file.yaml> [-f <more.yaml>...]",
+        };
+
+        let file_paths = collect_flag_values(&args, "-f");
+        if file_paths.len() != f_count {
+            exit_with_usage("-f flag requires a file path argument", usage);
+        }
+
+        for file_path in &file_paths {
             // Validate that the file exists and has a .yaml or .yml extension
             let path = Path::new(file_path);
             if !path.exists() {
-                eprintln!("Error: File not found: {}", file_path);
-                eprintln!("Usage: fonda -f <environment_file.yaml>");
-                std::process::exit(1);
+                exit_with_usage(&format!("File not found: {}", file_path), usage);
             }
             
             let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
             if extension != "yaml" && extension != "yml" {
-                eprintln!("Warning: File does not have .yaml or .yml extension: {}", file_path);
-                let _ = log_debug(&format!("Warning: File does not have .yaml or .yml extension: {}", file_path));
+                warn_println!("File does not have .yaml or .yml extension: {}", file_path);
             }
-            
-            command = FondaCommand::CustomFile(file_path.clone());
-            let _ = log_debug(&format!("Using -f with file: {}", file_path));
-        } else {
-            eprintln!("Error: -f flag requires a file path argument");
-            eprintln!("Usage: fonda -f <environment_file.yaml>");
-            std::process::exit(1);
         }
+
+        command = match command {
+            FondaCommand::WriteRequirements => FondaCommand::WriteRequirementsCustomFile(file_paths.clone()),
+            FondaCommand::CreateAndRun => FondaCommand::CustomFile(file_paths.clone()),
+            other => other,
+        };
+        debug_println!("Using -f with files: {}", file_paths.join(", "));
+        env_files = file_paths;
+    }
+
+    let options = FondaOptions {
+        env_dir: flag_value(&args, "--env"),
+        system: args.iter().any(|arg| arg == "--system"),
+        prefix: flag_value(&args, "--prefix"),
+        jupyter_kernel: args.iter().any(|arg| arg == "--jupyter-kernel"),
+        with_groups: flag_list(&args, "--with"),
+        without_groups: flag_list(&args, "--without").unwrap_or_default(),
+        offline: args.iter().any(|arg| arg == "--offline"),
+        wheelhouse: flag_value(&args, "--wheelhouse"),
+        force: args.iter().any(|arg| arg == "--force"),
+        dry_run: args.iter().any(|arg| arg == "--dry-run"),
+        platform: flag_value(&args, "--platform").map(|platform| match platform.as_str() {
+            "windows" | "win" => "windows".to_string(),
+            "linux" => "linux".to_string(),
+            "macos" | "osx" | "darwin" => "macos".to_string(),
+            other => exit_with_usage(&format!("unknown platform: {}", other), "--platform takes windows, linux or macos"),
+        }),
+    };
+    debug_println!("Options: {:?}", options);
+
+    if options.dry_run {
+        json_record(|report| report.dry_run = true);
+        return match command {
+            FondaCommand::RunRequirements(files) => {
+                let target = resolve_install_target(&options, &env_files)?;
+                plan_run_requirements(&files, &target, &options)
+            },
+            FondaCommand::CreateAndRun | FondaCommand::CustomFile(_) => plan_create(&env_files, &options).await,
+            FondaCommand::Update => plan_update(&env_files, &options).await,
+            _ => exit_with_usage(
+                "--dry-run is not supported by this command",
+                "--dry-run works with create, update and -r",
+            ),
+        };
     }
 
     match command {
-        FondaCommand::RunRequirements => run_requirements().await,
-        FondaCommand::WriteRequirements => write_requirements().await,
-        FondaCommand::WriteRequirementsCustomFile(file_path) => {
-            println!("Writing requirements from custom file: {}", file_path);
-            let _ = log_debug(&format!("Writing requirements from custom file: {}", file_path));
-            write_requirements_from_file(&file_path).await
+        FondaCommand::RunRequirements(files) => {
+            let target = resolve_install_target(&options, &env_files)?;
+            run_requirements(&files, &target, None, &options).await
         },
-        FondaCommand::CreateAndRun => create_and_run().await,
-        FondaCommand::CustomFile(file_path) => create_and_run_with_file(&file_path).await,
+        FondaCommand::WriteRequirements => write_requirements(&options).await,
+        FondaCommand::WriteRequirementsCustomFile(file_paths) => {
+            info_println!("Writing requirements from custom file: {}", file_paths.join(", "));
+            write_requirements_from_file(&file_paths, &options).await
+        },
+        FondaCommand::CreateAndRun => create_and_run(&options).await,
+        FondaCommand::CustomFile(file_paths) => create_and_run_with_file(&file_paths, &options).await,
+        FondaCommand::Env(action) => run_env_command(action, &env_files, &options).await,
+        FondaCommand::Hook(shell) => print_hook(&shell),
+        FondaCommand::Run(child) => {
+            let code = run_in_environment(&env_files, &options, &child).await?;
+            std::process::exit(code);
+        },
+        FondaCommand::Shell => {
+            let code = run_shell(&env_files, &options).await?;
+            std::process::exit(code);
+        },
+        FondaCommand::Update => update_with_file(&env_files, &options).await,
+        FondaCommand::Check => check_env_file(&env_files, &options).await,
+        FondaCommand::Download => download_requirements(&env_files, &options).await,
+        FondaCommand::Status => show_status(&env_files, &options).await,
+        FondaCommand::Why(package) => explain_requirement(&env_files, &package, &options),
+    }
+}
+
+/// Parses a subcommand such as `env list`
+///
+/// Returns `None` when the arguments do not start with a subcommand, and the
+/// usage text as the error when the subcommand is incomplete.
+fn parse_subcommand(args: &[&str]) -> Option<Result<FondaCommand, String>> {
+    match args {
+        ["env", rest @ ..] => Some(match rest {
+            ["list"] | ["ls"] => Ok(FondaCommand::Env(EnvAction::List)),
+            // Accepts -f/--prefix/--env like the other commands that find an environment
+            ["path", ..] => Ok(FondaCommand::Env(EnvAction::Path)),
+            ["info", name] => Ok(FondaCommand::Env(EnvAction::Info(name.to_string()))),
+            ["remove", name] | ["rm", name] => Ok(FondaCommand::Env(EnvAction::Remove(name.to_string()))),
+            _ => Err("Usage: fonda env list | fonda env path | fonda env info <name|path> | fonda env remove <name|path>".to_string()),
+        }),
+        ["hook", shell] => Some(Ok(FondaCommand::Hook(shell.to_string()))),
+        ["hook", ..] => Some(Err("Usage: fonda hook bash|zsh|fish|direnv".to_string())),
+        // The command itself is filled in from the arguments after `--`
+        ["run", ..] => Some(Ok(FondaCommand::Run(Vec::new()))),
+        ["shell", ..] => Some(Ok(FondaCommand::Shell)),
+        ["update", ..] => Some(Ok(FondaCommand::Update)),
+        ["check", ..] => Some(Ok(FondaCommand::Check)),
+        ["download", ..] => Some(Ok(FondaCommand::Download)),
+        ["status", ..] => Some(Ok(FondaCommand::Status)),
+        ["why", package, ..] if !package.starts_with('-') => Some(Ok(FondaCommand::Why(package.to_string()))),
+        ["why", ..] => Some(Err("Usage: fonda why <package> [-f <environment_file.yaml>] [--platform windows|linux|macos]".to_string())),
+        // Same as plain `fonda`, for symmetry with `fonda update`
+        ["create", ..] => Some(Ok(FondaCommand::CreateAndRun)),
+        _ => None,
+    }
+}
+
+/// Get the value following a single-valued flag (e.g. `--env ./myenv`)
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    collect_flag_values(args, flag).pop()
+}
+
+/// Opens the project's log file for appending, rotating it first if it is too large
+fn open_project_log() -> Result<(File, PathBuf), FondaError> {
+    let path = project_log_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("could not create {}", parent.display()))?;
+    }
+    rotate_log(&path).with_context(|| format!("could not rotate log file {}", path.display()))?;
+    let file = OpenOptions::new().create(true).append(true).open(&path)
+        .with_context(|| format!("could not open log file {}", path.display()))?;
+    Ok((file, path))
+}
+
+/// The log file for the project in the current directory:
+/// `<state dir>/logs/<directory name>-<hash of its path>.log`
+fn project_log_path() -> Result<PathBuf, FondaError> {
+    let cwd = std::env::current_dir().with_context(|| "could not determine the current directory".to_string())?;
+    let cwd = cwd.canonicalize().unwrap_or(cwd);
+    let name: String = cwd.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "root".to_string())
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+
+    Ok(user_state_dir()?.join("logs").join(format!("{}-{:08x}.log", name, path_hash(&cwd))))
+}
+
+/// A short hash of a path for file and kernel names
+///
+/// FNV-1a, so that the same path gives the same name across fonda versions.
+fn path_hash(path: &Path) -> u32 {
+    let hash = path.to_string_lossy().bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3));
+    hash as u32
+}
+
+/// Moves a log file past [`LOG_MAX_BYTES`] to `<file>.1`, shifting older rotations up
+/// and dropping the one beyond [`LOG_KEEP`]
+fn rotate_log(path: &Path) -> io::Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() >= LOG_MAX_BYTES => {}
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    let rotated = |index: usize| PathBuf::from(format!("{}.{}", path.display(), index));
+    for index in (1..LOG_KEEP).rev() {
+        if rotated(index).exists() {
+            std::fs::rename(rotated(index), rotated(index + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(1))
+}
+
+/// The index of the first argument after the program name that is neither a flag
+/// nor a flag's value: the subcommand, if there is one
+fn command_word_index(args: &[String]) -> Option<usize> {
+    let mut index = 1;
+    while let Some(arg) = args.get(index) {
+        if !arg.starts_with('-') {
+            return Some(index);
+        }
+        index += 1;
+        if VALUE_FLAGS.contains(&arg.as_str()) && args.get(index).is_some_and(|value| !value.starts_with('-')) {
+            index += 1;
+        }
+    }
+    None
+}
+
+/// Get the comma-separated values of a repeatable flag (e.g. `--with dev,test --with docs`)
+fn flag_list(args: &[String], flag: &str) -> Option<Vec<String>> {
+    let values = collect_flag_values(args, flag);
+    if values.is_empty() {
+        return None;
     }
+
+    Some(values.iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Collect the values following every occurrence of a repeatable flag (e.g. `-r base.txt -r dev.txt`)
+fn collect_flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.iter()
+        .enumerate()
+        .filter(|(_, arg)| arg.as_str() == flag)
+        .filter_map(|(index, _)| args.get(index + 1))
+        .filter(|value| !value.starts_with('-'))
+        .cloned()
+        .collect()
 }
 
 async fn run_command(command: &str, args: &[&str]) -> Result<std::process::Output, FondaError> {
+    run_command_with_env(command, args, &[]).await
+}
+
+/// Like `run_command`, with extra environment variables for the child process
+async fn run_command_with_env(
+    command: &str,
+    args: &[&str],
+    envs: &[(String, std::ffi::OsString)],
+) -> Result<std::process::Output, FondaError> {
     let start = Instant::now();
-    println!("Running command: {} {}", command, args.join(" "));
-    let _ = log_debug(&format!("Running command: {} {}", command, args.join(" ")));
+    info_println!("Running command: {}", command_line(command, args));
     
     let result = TokioCommand::new(command)
         .args(args)
+        .envs(envs.iter().map(|(name, value)| (name, value)))
         .output()
         .await
-        .map_err(|e| FondaError::CommandFailed {
-            command: format!("{} {}", command, args.join(" ")),
-            error: e.to_string(),
-        });
+        .map_err(|e| spawn_error(command, args, e));
 
-    println!("Command completed in {:?}", start.elapsed());
-    let _ = log_debug(&format!("Command completed in {:?}", start.elapsed()));
+    info_println!("Command completed in {:?}", start.elapsed());
+    json_record(|report| report.commands.push(JsonCommand {
+        command: command_line(command, args),
+        duration_ms: start.elapsed().as_millis() as u64,
+        exit_code: result.as_ref().ok().and_then(|output| output.status.code()),
+    }));
     result
 }
 
-async fn run_requirements() -> Result<(), FondaError> {
-    let requirements_path = Path::new(REQUIREMENTS_FILE);
-    if !requirements_path.exists() {
-        return Err(FondaError::RequirementsNotFound(format!("{} not found", REQUIREMENTS_FILE)));
+/// A command and its arguments as one line, for logs, `--json` and `--dry-run`
+fn command_line(command: &str, args: &[&str]) -> String {
+    format!("{} {}", command, args.join(" ")).trim_end().to_string()
+}
+
+/// Where `-r` installs requirements
+#[derive(Debug)]
+enum InstallTarget {
+    /// A virtual environment directory, installed into with its own interpreter
+    Environment(PathBuf),
+    /// Whatever pip is on PATH (only with --system)
+    System,
+}
+
+impl InstallTarget {
+    /// The program and arguments that run `pip <pip_args>` for this target: the
+    /// environment's own interpreter, or the pip on PATH for `--system`
+    fn pip_command<'a>(&self, pip_args: &[&'a str]) -> Result<(String, Vec<&'a str>), FondaError> {
+        match self {
+            InstallTarget::Environment(venv_path) => {
+                let python = sanitize_path(&venv_python(venv_path))?.to_string();
+                Ok((python, [&["-m", "pip"], pip_args].concat()))
+            }
+            InstallTarget::System if OS == "windows" => Ok(("python".to_string(), [&["-m", "pip"], pip_args].concat())),
+            InstallTarget::System => Ok(("pip".to_string(), pip_args.to_vec())),
+        }
     }
 
-    if OS == "windows" {
-        run_command(
-            "python",
-            &["-m", "pip", "install", "-r", sanitize_path(requirements_path)?]
-        ).await?;
-    } else {
-        run_command(
-            "pip",
-            &["install", "-r", sanitize_path(requirements_path)?]
-        ).await?;
+    /// The program and arguments that install `install_args` (`install -r ...`) from
+    /// an environment file
+    ///
+    /// pip takes each package from whichever index has the highest version, so it
+    /// cannot keep a package listed with its own `index:` to that index. When the
+    /// file has such packages and uv is installed, `uv pip install` is used instead:
+    /// with `--index-strategy first-index` uv takes a package only from the first
+    /// index that has it, and the extra indexes are searched before PyPI. Without
+    /// uv this warns that a same-named package elsewhere could be installed.
+    async fn install_command(&self, install_args: &[&str], env: Option<&CondaEnv>) -> Result<(String, Vec<String>), FondaError> {
+        let pinned = env.map(pinned_packages).unwrap_or_default();
+        if !pinned.is_empty() {
+            if let InstallTarget::Environment(venv_path) = self {
+                if uv_installed().await {
+                    let python = sanitize_path(&venv_python(venv_path))?.to_string();
+                    let mut args = vec!["pip".to_string()];
+                    args.extend(install_args.iter().map(|arg| arg.to_string()));
+                    args.extend(["--python".to_string(), python, "--index-strategy".to_string(), "first-index".to_string()]);
+                    return Ok(("uv".to_string(), args));
+                }
+            }
+            for (name, index) in &pinned {
+                warn_println!(
+                    "pip cannot restrict {} to {}: a package with the same name on another index (such as PyPI) \
+                     may be installed instead. Install uv to have fonda pin it to its index",
+                    name, index
+                );
+            }
+        }
+
+        let (program, args) = self.pip_command(install_args)?;
+        Ok((program, args.into_iter().map(str::to_string).collect()))
     }
+}
 
-    println!("Requirements installed successfully.");
-    let _ = log_debug("Requirements installed successfully.");
-    Ok(())
+/// Packages in `pip:` that name their own `index:`, as `(spec, index)`
+fn pinned_packages(env: &CondaEnv) -> Vec<(&str, &str)> {
+    env.pip.iter().flatten()
+        .filter_map(|entry| match entry {
+            PipEntry::Package { name, index: Some(index) } => Some((name.as_str(), index.as_str())),
+            _ => None,
+        })
+        .collect()
 }
 
-async fn write_requirements() -> Result<(), FondaError> {
-    println!("Writing requirements from default environment file: {}", ENVIRONMENT_FILE);
-    let _ = log_debug(&format!("Writing requirements from default environment file: {}", ENVIRONMENT_FILE));
-    write_requirements_from_file(ENVIRONMENT_FILE).await
+/// Whether uv is installed (`uv --version` runs)
+async fn uv_installed() -> bool {
+    TokioCommand::new("uv").arg("--version").output().await
+        .is_ok_and(|output| output.status.success())
 }
 
-async fn write_requirements_from_file(env_file: &str) -> Result<(), FondaError> {
-    debug_println!("DEBUG: Starting write_requirements_from_file with file: {}", env_file);
-    let path = Path::new(env_file);
-    if !path.exists() {
-        return Err(FondaError::ConfigNotFound(format!("{} not found", env_file)));
+/// Resolves the environment `-r` should install into
+///
+/// An explicit `--env <dir>` wins, then the environment described by the YAML file
+/// (see `resolve_env_path`). The system Python is only used when `--system` is given.
+fn resolve_install_target(options: &FondaOptions, env_files: &[String]) -> Result<InstallTarget, FondaError> {
+    if options.system {
+        return Ok(InstallTarget::System);
     }
 
-    // First, parse the YAML file to get the basic structure (for validation)
-    let file = File::open(path)?;
-    let _env: CondaEnv = serde_yaml::from_reader(file)?;
-    debug_println!("DEBUG: Successfully parsed YAML file structure");
+    let venv_path = resolve_existing_env(options, env_files)?;
+    debug_println!("Installing requirements into: {}", venv_path.display());
+    Ok(InstallTarget::Environment(venv_path))
+}
 
-    // Now, read the file as raw text to preserve comments
-    let file_content = std::fs::read_to_string(path)?;
-    debug_println!("DEBUG: Read raw file content");
+/// Resolves an existing virtual environment from `--env <dir>` or the YAML file
+///
+/// # Errors
+/// Returns `FondaError::EnvironmentNotFound` if neither is available or the
+/// directory has no Python interpreter.
+fn resolve_existing_env(options: &FondaOptions, env_files: &[String]) -> Result<PathBuf, FondaError> {
+    let venv_path = match &options.env_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            if let Some(missing) = env_files.iter().find(|file| !Path::new(file).exists()) {
+                return Err(FondaError::EnvironmentNotFound(format!(
+                    "no target environment: {} not found. Use --env <dir> or -f <file> to choose one, or --system to install into the system Python",
+                    missing
+                )));
+            }
+            let env = load_env_files(env_files)?;
+            validate_env_name(&env.name)?;
+            resolve_env_path(&env, options)
+        }
+    };
+
+    if !venv_python(&venv_path).exists() {
+        return Err(FondaError::EnvironmentNotFound(format!(
+            "{} is not a virtual environment (no interpreter at {})",
+            venv_path.display(),
+            venv_python(&venv_path).display()
+        )));
+    }
+
+    Ok(venv_path)
+}
+
+/// The absolute form of a path that must exist
+fn canonical_path(path: &Path) -> Result<PathBuf, FondaError> {
+    std::fs::canonicalize(path).with_context(|| format!("could not resolve {}", path.display()))
+}
+
+/// Resolves where an environment's virtual environment lives
+///
+/// In order of precedence: `--prefix`, the `prefix:` field of the YAML file,
+/// `$FONDA_ENVS_DIR/<name>`, and finally `./<name>`.
+fn resolve_env_path(env: &CondaEnv, options: &FondaOptions) -> PathBuf {
+    if let Some(prefix) = options.prefix.as_deref().or(env.prefix.as_deref()) {
+        return expand_home(prefix);
+    }
+
+    match std::env::var(ENVS_DIR_VAR) {
+        Ok(envs_dir) if !envs_dir.is_empty() => expand_home(&envs_dir).join(&env.name),
+        _ => PathBuf::from(&env.name),
+    }
+}
+
+/// Expands a leading `~` to the user's home directory
+fn expand_home(path: &str) -> PathBuf {
+    let home = std::env::var("HOME").or_else(|_| std::env::var("USERPROFILE"));
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') || rest.starts_with('\\') => {
+            PathBuf::from(home).join(rest.trim_start_matches(['/', '\\']))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Formats a venv script path for the activation hints, keeping relative paths explicit
+fn display_env_path(path: &Path) -> String {
+    if path.is_absolute() || path.starts_with(".") || path.starts_with("..") {
+        path.display().to_string()
+    } else {
+        Path::new(".").join(path).display().to_string()
+    }
+}
+
+/// Directory holding a virtual environment's executables
+fn venv_bin_dir(venv_path: &Path) -> PathBuf {
+    if OS == "windows" {
+        venv_path.join("Scripts")
+    } else {
+        venv_path.join("bin")
+    }
+}
+
+/// Path of the Python interpreter inside a virtual environment
+fn venv_python(venv_path: &Path) -> PathBuf {
+    if OS == "windows" {
+        venv_bin_dir(venv_path).join("python.exe")
+    } else {
+        venv_bin_dir(venv_path).join("python")
+    }
+}
+
+/// Runs a command inside an environment without activating it
+///
+/// Sets `VIRTUAL_ENV` and puts the environment's executables first on `PATH`
+/// for the child process only. Returns the child's exit code.
+async fn run_in_environment(env_files: &[String], options: &FondaOptions, child: &[String]) -> Result<i32, FondaError> {
+    let venv_path = canonical_path(&resolve_existing_env(options, env_files)?)?;
+    let variables = load_env_variables(env_files)?;
+
+    debug_println!("Running in {}: {}", venv_path.display(), child.join(" "));
+    let status = TokioCommand::new(&child[0])
+        .args(&child[1..])
+        .envs(environment_vars(&venv_path, &variables)?)
+        .env_remove("PYTHONHOME")
+        .status()
+        .await
+        .map_err(|e| spawn_error(&child[0], &[], e))?;
+
+    debug_println!("Command exited with {}", status);
+    Ok(exit_code(status))
+}
+
+/// Variables that put a child process inside an environment: `VIRTUAL_ENV`,
+/// `PATH` with the environment's executables first, and the YAML `variables:`
+fn environment_vars(venv_path: &Path, variables: &[(String, String)]) -> Result<Vec<(String, std::ffi::OsString)>, FondaError> {
+    let mut paths = vec![venv_bin_dir(venv_path)];
+    if let Some(path) = std::env::var_os("PATH") {
+        paths.extend(std::env::split_paths(&path));
+    }
+    let path = std::env::join_paths(paths).map_err(|e| FondaError::CommandFailed {
+        command: "PATH".to_string(),
+        error: format!("Invalid PATH: {}", e),
+    })?;
+
+    let mut vars = vec![
+        ("VIRTUAL_ENV".to_string(), venv_path.as_os_str().to_os_string()),
+        ("PATH".to_string(), path),
+    ];
+    vars.extend(variables.iter().map(|(name, value)| (name.clone(), value.into())));
+    Ok(vars)
+}
+
+/// Starts the user's shell inside an environment, with the environment name as a prompt prefix
+///
+/// Supports bash, zsh, fish and POSIX sh; other shells still get `VIRTUAL_ENV` and
+/// `PATH` but keep their own prompt. Exiting the shell returns to the original
+/// environment. Returns the shell's exit code.
+async fn run_shell(env_files: &[String], options: &FondaOptions) -> Result<i32, FondaError> {
+    let venv_path = canonical_path(&resolve_existing_env(options, env_files)?)?;
+    let variables = load_env_variables(env_files)?;
+    let name = match &options.env_dir {
+        Some(_) => venv_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+        None => load_env_files(env_files)?.name,
+    };
+
+    if std::env::var_os("FONDA_SHELL").is_some() {
+        warn_println!("Already inside a fonda shell; exit it first to avoid nesting environments");
+    }
+
+    let shell = match std::env::var("SHELL") {
+        Ok(shell) if !shell.is_empty() => shell,
+        _ if OS == "windows" => std::env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string()),
+        _ => "/bin/sh".to_string(),
+    };
+    let shell_name = Path::new(&shell)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    // Startup files that load the user's own configuration, then put the environment
+    // back in front of PATH (user rc files often prepend to it) and add the prompt prefix
+    let bin_dir = venv_bin_dir(&venv_path).display().to_string();
+    let rc_dir = create_private_temp_dir("fonda-shell")?;
+    let prompt = format!("({}) ", name);
+    let home = std::env::var("HOME").unwrap_or_default();
+
+    let mut command = TokioCommand::new(&shell);
+    match shell_name.as_str() {
+        "bash" => {
+            let rc_file = rc_dir.join("bashrc");
+            std::fs::write(&rc_file, format!(
+                "[ -f \"$HOME/.bashrc\" ] && . \"$HOME/.bashrc\"\nexport PATH=\"{}:$PATH\"\nPS1=\"{}$PS1\"\n",
+                bin_dir, prompt
+            )).with_context(|| format!("could not write shell startup file in {}", rc_dir.display()))?;
+            command.arg("--rcfile").arg(&rc_file).arg("-i");
+        }
+        "zsh" => {
+            let zdotdir = std::env::var("ZDOTDIR").unwrap_or(home);
+            std::fs::write(rc_dir.join(".zshenv"), format!(
+                "[ -f \"{0}/.zshenv\" ] && . \"{0}/.zshenv\"\n",
+                zdotdir
+            )).with_context(|| format!("could not write shell startup file in {}", rc_dir.display()))?;
+            std::fs::write(rc_dir.join(".zshrc"), format!(
+                "ZDOTDIR=\"{0}\"\n[ -f \"{0}/.zshrc\" ] && . \"{0}/.zshrc\"\nexport PATH=\"{1}:$PATH\"\nPS1=\"{2}$PS1\"\n",
+                zdotdir, bin_dir, prompt
+            )).with_context(|| format!("could not write shell startup file in {}", rc_dir.display()))?;
+            command.env("ZDOTDIR", &rc_dir).arg("-i");
+        }
+        "fish" => {
+            command.arg("--init-command").arg(format!(
+                "set -gx PATH '{}' $PATH; functions -c fish_prompt _fonda_fish_prompt; function fish_prompt; echo -n '{}'; _fonda_fish_prompt; end",
+                bin_dir, prompt
+            ));
+        }
+        "sh" | "dash" | "ash" | "ksh" => {
+            let rc_file = rc_dir.join("shrc");
+            let user_env = std::env::var("ENV").unwrap_or_default();
+            std::fs::write(&rc_file, format!(
+                "[ -n \"{0}\" ] && [ -f \"{0}\" ] && . \"{0}\"\nexport PATH=\"{1}:$PATH\"\nPS1=\"{2}${{PS1:-$ }}\"\n",
+                user_env, bin_dir, prompt
+            )).with_context(|| format!("could not write shell startup file in {}", rc_dir.display()))?;
+            command.env("ENV", &rc_file).arg("-i");
+        }
+        "cmd" => {
+            command.env("PROMPT", format!("{}$P$G", prompt));
+        }
+        _ => {
+            info_println!("Note: {} is not a supported shell, the prompt will not show the environment", shell);
+        }
+    }
+
+    info_println!("Starting {} in environment '{}' ({}). Type 'exit' to leave.", shell_name, name, venv_path.display());
+
+    let status = command
+        .envs(environment_vars(&venv_path, &variables)?)
+        .env("FONDA_SHELL", &name)
+        .env_remove("PYTHONHOME")
+        .status()
+        .await;
+    let _ = std::fs::remove_dir_all(&rc_dir);
+
+    let status = status.map_err(|e| spawn_error(&shell, &[], e))?;
+
+    info_println!("Left environment '{}'.", name);
+    Ok(exit_code(status))
+}
+
+/// Creates a new directory in the temp dir that only the current user can access
+///
+/// The name is unpredictable and the directory must not exist yet, so another
+/// user cannot prepare it (or a symlink in its place) to plant files in it.
+///
+/// # Errors
+/// Returns `FondaError::Io` if no new directory could be created.
+fn create_private_temp_dir(prefix: &str) -> Result<PathBuf, FondaError> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    let mut attempt: u32 = 0;
+    loop {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or(0);
+        let dir = std::env::temp_dir().join(format!("{}-{}-{:08x}", prefix, std::process::id(), nanos ^ attempt.wrapping_mul(0x9e37_79b9)));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 16 => attempt += 1,
+            Err(e) => return Err(FondaError::Io { context: format!("could not create directory {}", dir.display()), error: e }),
+        }
+    }
+}
+
+/// The YAML `variables:` as name/value pairs, with scalar values converted to strings
+///
+/// # Errors
+/// Returns `FondaError::InvalidConfig` for names that are not valid shell variable
+/// names or values that are not scalars.
+fn env_variables(env: &CondaEnv) -> Result<Vec<(String, String)>, FondaError> {
+    let mut variables = Vec::new();
+    for (name, value) in env.variables.iter().flatten() {
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(FondaError::InvalidConfig(format!(
+                "invalid variable name '{}': use letters, digits and underscores", name
+            )));
+        }
+
+        let value = match value {
+            serde_yaml::Value::String(value) => value.clone(),
+            serde_yaml::Value::Number(value) => value.to_string(),
+            serde_yaml::Value::Bool(value) => value.to_string(),
+            serde_yaml::Value::Null => String::new(),
+            _ => {
+                return Err(FondaError::InvalidConfig(format!(
+                    "variable '{}' must be a string, number or boolean", name
+                )));
+            }
+        };
+        variables.push((name.clone(), value));
+    }
+    Ok(variables)
+}
+
+/// The YAML `variables:` of the environment files, or none when there is no file
+/// (e.g. with `--env <dir>`)
+fn load_env_variables(env_files: &[String]) -> Result<Vec<(String, String)>, FondaError> {
+    if env_files.iter().any(|file| !Path::new(file).exists()) {
+        return Ok(Vec::new());
+    }
+    env_variables(&load_env_files(env_files)?)
+}
+
+const VARIABLES_BEGIN: &str = ">>> fonda variables >>>";
+const VARIABLES_END: &str = "<<< fonda variables <<<";
+
+/// Writes the YAML variables into the venv's activate scripts
+///
+/// Each script saves any previous value on activation, exports the new one, and
+/// restores (or unsets) it from `deactivate`. Blocks are delimited by markers so
+/// rewriting replaces rather than duplicates them. Missing scripts are skipped,
+/// since uv and venv do not create the same set.
+fn write_activation_variables(venv_path: &Path, variables: &[(String, String)]) -> Result<(), FondaError> {
+    let bin_dir = venv_bin_dir(venv_path);
+
+    let sh_quote = |value: &str| format!("'{}'", value.replace('\'', "'\\''"));
+    let fish_quote = |value: &str| format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"));
+    let ps_quote = |value: &str| format!("'{}'", value.replace('\'', "''"));
+    let bat_escape = |value: &str| value.replace('%', "%%");
+
+    // bin/activate (bash, zsh, sh)
+    let mut restore = String::from("    if [ -n \"${_FONDA_VARIABLES_ACTIVE:-}\" ] ; then\n");
+    let mut export = String::from("_FONDA_VARIABLES_ACTIVE=1\n");
+    for (name, value) in variables {
+        restore.push_str(&format!(
+            "        if [ -n \"${{_FONDA_OLD_{0}+x}}\" ] ; then export {0}=\"$_FONDA_OLD_{0}\" ; unset _FONDA_OLD_{0} ; else unset {0} ; fi\n",
+            name
+        ));
+        export.push_str(&format!("if [ -n \"${{{0}+x}}\" ] ; then _FONDA_OLD_{0}=\"${0}\" ; fi\nexport {0}={1}\n", name, sh_quote(value)));
+    }
+    restore.push_str("        unset _FONDA_VARIABLES_ACTIVE\n    fi\n");
+    patch_activate_script(&bin_dir.join("activate"), "#", "deactivate ()", &restore, &export)?;
+
+    // activate.fish
+    let mut restore = String::from("    if set -q _FONDA_VARIABLES_ACTIVE\n");
+    let mut export = String::from("set -g _FONDA_VARIABLES_ACTIVE 1\n");
+    for (name, value) in variables {
+        restore.push_str(&format!(
+            "        if set -q _FONDA_OLD_{0}; set -gx {0} $_FONDA_OLD_{0}; set -e _FONDA_OLD_{0}; else; set -e {0}; end\n",
+            name
+        ));
+        export.push_str(&format!("if set -q {0}; set -g _FONDA_OLD_{0} ${0}; end\nset -gx {0} {1}\n", name, fish_quote(value)));
+    }
+    restore.push_str("        set -e _FONDA_VARIABLES_ACTIVE\n    end\n");
+    patch_activate_script(&bin_dir.join("activate.fish"), "#", "function deactivate", &restore, &export)?;
+
+    // Activate.ps1 (venv) or activate.ps1 (uv)
+    let mut restore = String::from("    if (Test-Path variable:global:_FONDA_VARIABLES_ACTIVE) {\n");
+    let mut export = String::from("$global:_FONDA_VARIABLES_ACTIVE = $true\n");
+    for (name, value) in variables {
+        restore.push_str(&format!(
+            "        if (Test-Path variable:global:_FONDA_OLD_{0}) {{ $env:{0} = $global:_FONDA_OLD_{0}; Remove-Variable -Name _FONDA_OLD_{0} -Scope Global }} else {{ Remove-Item -Path env:{0} -ErrorAction SilentlyContinue }}\n",
+            name
+        ));
+        export.push_str(&format!("if (Test-Path env:{0}) {{ $global:_FONDA_OLD_{0} = $env:{0} }}\n$env:{0} = {1}\n", name, ps_quote(value)));
+    }
+    restore.push_str("        Remove-Variable -Name _FONDA_VARIABLES_ACTIVE -Scope Global\n    }\n");
+    for script in ["Activate.ps1", "activate.ps1"] {
+        patch_activate_script(&bin_dir.join(script), "#", "function global:deactivate", &restore, &export)?;
+    }
+
+    // activate.bat / deactivate.bat (cmd.exe cannot tell empty from unset)
+    let mut restore = String::from("if defined _FONDA_VARIABLES_ACTIVE (\n");
+    let mut export = String::from("set \"_FONDA_VARIABLES_ACTIVE=1\"\n");
+    for (name, value) in variables {
+        restore.push_str(&format!("    set \"{0}=%_FONDA_OLD_{0}%\"\n    set \"_FONDA_OLD_{0}=\"\n", name));
+        export.push_str(&format!("set \"_FONDA_OLD_{0}=%{0}%\"\nset \"{0}={1}\"\n", name, bat_escape(value)));
+    }
+    restore.push_str("    set \"_FONDA_VARIABLES_ACTIVE=\"\n)\n");
+    patch_activate_script(&bin_dir.join("activate.bat"), "rem", "", "", &export)?;
+    patch_activate_script(&bin_dir.join("deactivate.bat"), "rem", "", "", &restore)?;
+
+    debug_println!("Wrote {} variables into the activate scripts of {}", variables.len(), venv_path.display());
+    Ok(())
+}
+
+/// Adds fonda's blocks to one activate script
+///
+/// `restore` goes right after the line starting with `deactivate_line` (skipped
+/// when that is empty or not found) and `export` goes at the end, before any
+/// PowerShell signature block. Existing fonda blocks are removed first.
+fn patch_activate_script(
+    script: &Path,
+    comment: &str,
+    deactivate_line: &str,
+    restore: &str,
+    export: &str,
+) -> Result<(), FondaError> {
+    if !script.exists() {
+        return Ok(());
+    }
+
+    let begin = format!("{} {}", comment, VARIABLES_BEGIN);
+    let end = format!("{} {}", comment, VARIABLES_END);
+    let content = std::fs::read_to_string(script).with_context(|| format!("could not read {}", script.display()))?;
+    let newline = if content.contains("\r\n") { "\r\n" } else { "\n" };
+
+    // Drop blocks from a previous run
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_block = false;
+    for line in content.lines() {
+        match line.trim() {
+            trimmed if trimmed == begin => in_block = true,
+            trimmed if trimmed == end => in_block = false,
+            _ if in_block => {}
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let block = |body: &str| {
+        let mut block = vec![begin.clone()];
+        block.extend(body.lines().map(str::to_string));
+        block.push(end.clone());
+        block
+    };
+
+    if !deactivate_line.is_empty() && !restore.is_empty() {
+        match lines.iter().position(|line| line.trim_start().starts_with(deactivate_line)) {
+            Some(index) => {
+                let insert_at = index + 1;
+                lines.splice(insert_at..insert_at, block(restore));
+            }
+            None => {
+                warn_println!("No deactivate function in {}, variables will not be unset on deactivation", script.display());
+            }
+        }
+    }
+
+    let signature = lines.iter()
+        .position(|line| line.starts_with("# SIG # Begin signature block"))
+        .unwrap_or(lines.len());
+    lines.splice(signature..signature, block(export));
+
+    let mut content = lines.join(newline);
+    content.push_str(newline);
+    std::fs::write(script, content).with_context(|| format!("could not write {}", script.display()))?;
+    Ok(())
+}
+
+/// Exit code to propagate for a finished child process
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Installs one or more requirements files with a single pip call
+///
+/// When the requirements come from an environment file, `env` supplies its pip
+/// options (see `pip_options`). With `--offline` only the wheelhouse is used.
+///
+/// # Errors
+/// Returns `FondaError::RequirementsNotFound` listing every missing file if any
+/// of them do not exist, before pip is invoked, `FondaError::OfflineInstallFailed`
+/// if an offline install is missing packages, and `FondaError::CommandFailed` if pip fails.
+async fn run_requirements(
+    files: &[String],
+    target: &InstallTarget,
+    env: Option<&CondaEnv>,
+    options: &FondaOptions,
+) -> Result<(), FondaError> {
+    let missing: Vec<&str> = files.iter()
+        .map(String::as_str)
+        .filter(|file| !Path::new(file).exists())
+        .collect();
+    if !missing.is_empty() {
+        return Err(FondaError::RequirementsNotFound(format!("{} not found", missing.join(", "))));
+    }
+    let option_args = install_source_args(env, options)?;
+
+    let mut pip_args = vec!["install"];
+    for file in files {
+        pip_args.push("-r");
+        pip_args.push(sanitize_path(Path::new(file))?);
+    }
+    pip_args.extend(option_args.iter().map(String::as_str));
+
+    if let InstallTarget::Environment(venv_path) = target {
+        let path = std::fs::canonicalize(venv_path).unwrap_or_else(|_| venv_path.clone());
+        json_record_environment(|json_env| json_env.path = Some(path));
+    }
+    let (installer, install_args) = target.install_command(&pip_args, env).await?;
+    let install_args: Vec<&str> = install_args.iter().map(String::as_str).collect();
+    let output = run_command(&installer, &install_args).await?;
+    check_pip_install(&output, options)?;
+
+    info_println!("Requirements installed successfully.");
+    Ok(())
+}
+
+async fn write_requirements(options: &FondaOptions) -> Result<(), FondaError> {
+    info_println!("Writing requirements from default environment file: {}", ENVIRONMENT_FILE);
+    write_requirements_from_file(&[ENVIRONMENT_FILE.to_string()], options).await
+}
+
+/// Selects the dependency groups to install from `--with` and `--without`
+///
+/// Without `--with` every group is selected; `--without` then removes groups.
+///
+/// # Errors
+/// Returns `FondaError::InvalidConfig` for group names the environment file does not define.
+fn select_groups(env: &CondaEnv, options: &FondaOptions) -> Result<Vec<String>, FondaError> {
+    let available: Vec<String> = env.groups.iter().flatten().map(|(name, _)| name.clone()).collect();
+
+    let requested = options.with_groups.iter().flatten().chain(&options.without_groups);
+    let unknown: Vec<&str> = requested
+        .filter(|name| !available.contains(name))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        return Err(FondaError::InvalidConfig(format!(
+            "unknown dependency group(s): {} (available: {})",
+            unknown.join(", "),
+            if available.is_empty() { "none".to_string() } else { available.join(", ") }
+        )));
+    }
+
+    Ok(available.into_iter()
+        .filter(|name| options.with_groups.as_ref().is_none_or(|with| with.contains(name)))
+        .filter(|name| !options.without_groups.contains(name))
+        .collect())
+}
+
+async fn write_requirements_from_file(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    debug_println!("Starting write_requirements_from_file with file: {}", env_files.join(", "));
+    let (env, requirements) = generate_requirements(env_files, options)?;
+
+    // Render everything first, so that an error leaves the old requirements.txt untouched
+    let content = render_requirements(&env, &requirements);
+    write_file_atomically(Path::new(REQUIREMENTS_FILE), &content)?;
+    debug_println!("Wrote requirements.txt file");
+
+    json_record_environment(|json_env| json_env.name = Some(env.name.clone()));
+    json_record(|report| report.requirements_file = Some(REQUIREMENTS_FILE.to_string()));
+    json_record_requirements(&requirements);
+
+    debug_println!("Finished processing all dependencies");
+    info_println!("requirements.txt created successfully.");
+    Ok(())
+}
+
+/// Loads the environment files and works out which requirements they include
+/// on this platform, warning about channels without a package index
+fn generate_requirements(env_files: &[String], options: &FondaOptions) -> Result<(CondaEnv, Requirements), FondaError> {
+    // First, parse the YAML files and their includes to get the basic structure (for validation)
+    let chains = load_env_chains(env_files)?;
+    let env = merge_env_chains(&chains);
+    debug_println!("Successfully parsed YAML file structure");
+
+    let requirements = collect_requirements(&chains, &env, options)?;
+    for channel in pip_options(&env).1 {
+        warn_println!("channel '{}' has no package index and is ignored (map it under channel_indexes:)", channel);
+    }
+    Ok((env, requirements))
+}
+
+/// Replaces a file's contents all at once: the content goes to a temporary file in
+/// the same directory, which is then renamed over the file. If anything fails (or
+/// fonda is interrupted) the old file is left as it was.
+///
+/// # Errors
+/// Returns `FondaError::Io` if the temporary file cannot be written or renamed;
+/// the temporary file is removed again.
+fn write_file_atomically(path: &Path, content: &str) -> Result<(), FondaError> {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let write = || -> io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    };
+    write().map_err(|error| {
+        let _ = std::fs::remove_file(&temp_path);
+        FondaError::Io { context: format!("could not write {}", path.display()), error }
+    })
+}
+
+/// The contents of requirements.txt: the pip options, then one included requirement per line
+fn render_requirements(env: &CondaEnv, requirements: &Requirements) -> String {
+    let mut content = String::new();
+
+    // pip reads these options from the top of the requirements file
+    for (flag, value) in pip_options(env).0 {
+        content.push_str(&format!("{} {}\n", flag, value));
+    }
+    for requirement in &requirements.included {
+        content.push_str(&requirement.spec);
+        content.push('\n');
+    }
+    content
+}
+
+/// The pip options for an environment: package indexes, find-links and trusted hosts
+///
+/// Indexes come from `index_url:`, the channels and per-package `index:` entries.
+/// A channel is a URL, a name under `channel_indexes:`, or a built-in name from
+/// `CHANNEL_INDEXES`. `index_url:` replaces PyPI as the main index; otherwise PyPI
+/// stays the main index unless a channel maps to it, in which case the first
+/// mapped channel becomes `--index-url`. Every other index is an `--extra-index-url`,
+/// since pip cannot restrict a package to one index.
+///
+/// Returns the options as `(flag, value)` pairs and the channels that could not be mapped.
+fn pip_options(env: &CondaEnv) -> (Vec<(&'static str, String)>, Vec<String>) {
+    let mut urls: Vec<String> = Vec::new();
+    let mut unmapped = Vec::new();
+    for channel in env.channels.iter().flatten() {
+        let url = if channel.starts_with("http://") || channel.starts_with("https://") {
+            Some(channel.clone())
+        } else {
+            env.channel_indexes.as_ref()
+                .and_then(|indexes| indexes.get(channel).cloned())
+                .or_else(|| CHANNEL_INDEXES.iter()
+                    .find(|(name, _)| name == channel)
+                    .map(|(_, url)| url.to_string()))
+        };
+        match url {
+            Some(url) if !urls.contains(&url) => urls.push(url),
+            Some(_) => {}
+            None => unmapped.push(channel.clone()),
+        }
+    }
+
+    let main_index = match &env.index_url {
+        Some(index_url) => Some(index_url.clone()),
+        None if urls.iter().any(|url| url.trim_end_matches('/') == PYPI_INDEX) => Some(urls.remove(0)),
+        None => None,
+    };
+    for entry in env.pip.iter().flatten() {
+        if let PipEntry::Package { index: Some(index), .. } = entry {
+            if !urls.contains(index) {
+                urls.push(index.clone());
+            }
+        }
+    }
+
+    let mut options = Vec::new();
+    if let Some(main_index) = main_index {
+        urls.retain(|url| *url != main_index);
+        options.push(("--index-url", main_index));
+    }
+    options.extend(urls.into_iter().map(|url| ("--extra-index-url", url)));
+    options.extend(env.find_links.iter().flat_map(OneOrMany::items).map(|link| ("--find-links", link.clone())));
+    options.extend(env.trusted_host.iter().flat_map(OneOrMany::items).map(|host| ("--trusted-host", host.clone())));
+    (options, unmapped)
+}
+
+/// The pip options as install arguments
+fn pip_option_args(env: &CondaEnv) -> Vec<String> {
+    pip_options(env).0.into_iter()
+        .flat_map(|(flag, value)| [flag.to_string(), value])
+        .collect()
+}
+
+/// The pip arguments that choose where packages are installed from
+///
+/// With `--offline` this is only the wheelhouse (`--no-index --find-links`).
+/// Otherwise it is the environment's pip options, plus the wheelhouse as an extra
+/// `--find-links` when `--wheelhouse` is given.
+///
+/// # Errors
+/// Returns `FondaError::OfflineInstallFailed` if the offline wheelhouse does not exist.
+fn install_source_args(env: Option<&CondaEnv>, options: &FondaOptions) -> Result<Vec<String>, FondaError> {
+    let wheelhouse = options.wheelhouse.as_deref().unwrap_or(WHEELHOUSE_DIR);
+    if options.offline {
+        if !Path::new(wheelhouse).is_dir() {
+            return Err(FondaError::OfflineInstallFailed(format!(
+                "wheelhouse {} does not exist. Run `fonda download --wheelhouse {}` on a connected machine first",
+                wheelhouse, wheelhouse
+            )));
+        }
+        return Ok(vec!["--no-index".to_string(), "--find-links".to_string(), wheelhouse.to_string()]);
+    }
+
+    let mut args = env.map(pip_option_args).unwrap_or_default();
+    if options.wheelhouse.is_some() {
+        args.extend(["--find-links".to_string(), wheelhouse.to_string()]);
+    }
+    Ok(args)
+}
+
+/// Turns a failed pip install into an error
+///
+/// Offline, the error names the packages missing from the wheelhouse.
+///
+/// # Errors
+/// Returns `FondaError::OfflineInstallFailed` for a failed `--offline` install and
+/// `FondaError::CommandFailed` with pip's last error line otherwise.
+fn check_pip_install(output: &std::process::Output, options: &FondaOptions) -> Result<(), FondaError> {
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !options.offline {
+        return Err(FondaError::CommandFailed {
+            command: "pip install".to_string(),
+            error: stderr.trim().lines().last()
+                .map_or_else(|| format!("pip exited with {}", output.status), str::to_string),
+        });
+    }
+    let missing: Vec<&str> = stderr.lines()
+        .filter_map(|line| line.split_once("No matching distribution found for "))
+        .map(|(_, requirement)| requirement.trim())
+        .collect();
+    let wheelhouse = options.wheelhouse.as_deref().unwrap_or(WHEELHOUSE_DIR);
+    Err(FondaError::OfflineInstallFailed(if missing.is_empty() {
+        stderr.trim().lines().last().unwrap_or("pip failed").to_string()
+    } else {
+        format!(
+            "not in wheelhouse {}: {}. Run `fonda download --wheelhouse {}` on a connected machine to add them",
+            wheelhouse, missing.join(", "), wheelhouse
+        )
+    }))
+}
+
+/// Downloads every requirement of the environment file into the wheelhouse
+///
+/// Writes requirements.txt first, then runs `pip download` with the environment's
+/// pip options, so the wheels match this platform and Python version. When a
+/// Jupyter kernel is requested, ipykernel is downloaded too, so that an offline
+/// create can register it.
+///
+/// # Errors
+/// Returns `FondaError::CommandFailed` with pip's error if a download fails.
+async fn download_requirements(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    let env = load_env_files(env_files)?;
+    write_requirements_from_file(env_files, options).await?;
+
+    let wheelhouse = options.wheelhouse.as_deref().unwrap_or(WHEELHOUSE_DIR);
+    std::fs::create_dir_all(wheelhouse).with_context(|| format!("could not create directory {}", wheelhouse))?;
+
+    let python_command = get_python_command().await?;
+    let option_args = pip_option_args(&env);
+    let mut pip_args = vec!["-m", "pip", "download", "-r", REQUIREMENTS_FILE, "-d", wheelhouse];
+    if options.jupyter_kernel || env.jupyter_kernel.unwrap_or(false) {
+        pip_args.push("ipykernel");
+    }
+    pip_args.extend(option_args.iter().map(String::as_str));
+    let output = run_command(python_command, &pip_args).await?;
+    if !output.status.success() {
+        return Err(FondaError::CommandFailed {
+            command: "pip download".to_string(),
+            error: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    let count = std::fs::read_dir(wheelhouse)
+        .with_context(|| format!("could not read directory {}", wheelhouse))?
+        .count();
+    info_println!("Wheelhouse {} now holds {} files.", wheelhouse, count);
+    info_println!("Copy it to the offline machine and run: fonda create --offline --wheelhouse {}", wheelhouse);
+    Ok(())
+}
+
+/// A requirement collected from an environment file
+#[derive(Debug, Clone)]
+struct Requirement {
+    /// The line written to requirements.txt
+    spec: String,
+    /// Environment file the requirement came from
+    source: PathBuf,
+    /// Line number in the source file
+    line: usize,
+    /// Earlier specs of the same package that this one replaced, as `file:line spec`
+    overrides: Vec<String>,
+    /// Why the requirement was included or skipped
+    reason: String,
+    /// The platform marker on the line and how it evaluated, e.g. `[win] does not match linux`
+    selector: Option<String>,
+    /// How the spec differs from what the line says, e.g. a `${VAR}` expansion
+    changes: Vec<String>,
+}
+
+impl Requirement {
+    fn new(spec: &str, source: &Path, line: usize, reason: &str) -> Self {
+        Requirement {
+            spec: spec.to_string(),
+            source: source.to_path_buf(),
+            line,
+            overrides: Vec::new(),
+            reason: reason.to_string(),
+            selector: None,
+            changes: Vec::new(),
+        }
+    }
+
+    /// `file:line` of the requirement, relative to the current directory when possible
+    fn location(&self) -> String {
+        format!("{}:{}", display_source(&self.source), self.line)
+    }
+}
+
+/// The requirements written to requirements.txt, and the ones left out
+#[derive(Debug, Default)]
+struct Requirements {
+    included: Vec<Requirement>,
+    skipped: Vec<Requirement>,
+}
+
+/// Normalized package name used to match specs of the same package, e.g.
+/// `Typing_Extensions>=4.0` and `typing-extensions==4.8` both give `typing-extensions`.
+/// Git, URL and editable requirements are matched on the whole spec.
+fn package_key(spec: &str) -> String {
+    if spec.starts_with("-e ") || spec.contains("://") || spec.starts_with("git+") {
+        return spec.to_string();
+    }
+
+    spec.split(|c: char| "<>=!~;[ @".contains(c))
+        .next()
+        .unwrap_or(spec)
+        .trim()
+        .to_lowercase()
+        .replace(['_', '.'], "-")
+}
+
+/// Collects the requirements for the current platform from the include chains of every `-f` file
+///
+/// Within one chain, files are processed in order (included files first) and a
+/// later spec for the same package (see `package_key`) replaces the earlier one.
+/// Across `-f` files the requirements are unioned instead.
+///
+/// # Errors
+/// Returns `FondaError::DependencyConflict` listing every package that two `-f`
+/// files specify differently.
+fn collect_requirements(
+    chains: &[Vec<(PathBuf, CondaEnv)>],
+    env: &CondaEnv,
+    options: &FondaOptions,
+) -> Result<Requirements, FondaError> {
+    let selected_groups = select_groups(env, options)?;
+    debug_println!("Selected dependency groups: {:?}", selected_groups);
+    let target_os = options.platform.as_deref().unwrap_or(OS);
+
+    let mut requirements = Requirements::default();
+    let mut conflicts = Vec::new();
+    for chain in chains {
+        let chain_requirements = collect_chain_requirements(chain, &selected_groups, target_os)?;
+        requirements.skipped.extend(chain_requirements.skipped);
+        for mut requirement in chain_requirements.included {
+            let key = package_key(&requirement.spec);
+            match requirements.included.iter().find(|existing| package_key(&existing.spec) == key) {
+                Some(existing) if existing.spec != requirement.spec => {
+                    conflicts.push(format!(
+                        "{} ({}) vs {} ({})",
+                        existing.spec, existing.location(), requirement.spec, requirement.location()
+                    ));
+                }
+                Some(existing) => {
+                    debug_println!("{} is already required, skipping duplicate", requirement.spec);
+                    requirement.reason = format!("duplicate of {}", existing.location());
+                    requirements.skipped.push(requirement);
+                }
+                None => requirements.included.push(requirement),
+            }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(FondaError::DependencyConflict(conflicts.join("; ")));
+    }
+    Ok(requirements)
+}
+
+/// Collects the requirements of one include chain, letting later specs override earlier ones
+fn collect_chain_requirements(
+    chain: &[(PathBuf, CondaEnv)],
+    selected_groups: &[String],
+    target_os: &str,
+) -> Result<Requirements, FondaError> {
+    let mut requirements = Requirements::default();
+    for (source, file_env) in chain {
+        // Read the file as raw text to preserve comments
+        let file_content = std::fs::read_to_string(source)
+            .with_context(|| format!("could not read {}", source.display()))?;
+        trace_println!("Read raw file content of {}", source.display());
+
+        let scanned = scan_requirements(source, &file_content, file_env, selected_groups, target_os)?;
+        requirements.skipped.extend(scanned.skipped);
+        for mut requirement in scanned.included {
+            let key = package_key(&requirement.spec);
+            match requirements.included.iter_mut().find(|existing| package_key(&existing.spec) == key) {
+                Some(existing) => {
+                    debug_println!("{} at {} overrides {} at {}", requirement.spec, requirement.location(), existing.spec, existing.location());
+                    requirement.overrides = std::mem::take(&mut existing.overrides);
+                    requirement.overrides.push(format!("{} {}", existing.location(), existing.spec));
+                    let mut replaced = std::mem::replace(existing, requirement);
+                    replaced.reason = format!("overridden by {}", existing.location());
+                    requirements.skipped.push(replaced);
+                }
+                None => requirements.included.push(requirement),
+            }
+        }
+    }
+
+    Ok(requirements)
+}
+
+/// Scans the raw text of one environment file for the requirements of the target platform
+///
+/// The YAML parser drops comments, so platform markers such as `# [win]` are read here.
+/// Requirements for other platforms or unselected groups are returned as skipped.
+/// `target_os` uses the names of `std::env::consts::OS` (windows, linux, macos).
+///
+/// Inline `dependencies:`, `pip:` and `groups:` values (e.g. `pip: [black, mypy]`) have
+/// no per-item lines or comments, so their specs come from the parsed file, at the
+/// line of the key and without platform markers.
+///
+/// Specs read from raw lines are expanded (see `expand_raw_spec`) before their markers
+/// are evaluated. Specs taken from the parsed file (inline values and pip mapping
+/// entries) were already expanded when it was loaded, so they are used as they are.
+///
+/// # Errors
+/// Returns `FondaError::UndefinedVariable` for a raw spec that cannot be expanded.
+fn scan_requirements(
+    source: &Path,
+    file_content: &str,
+    env: &CondaEnv,
+    selected_groups: &[String],
+    target_os: &str,
+) -> Result<Requirements, FondaError> {
+    let mut requirements = Requirements::default();
 
-    let requirements_path = Path::new(REQUIREMENTS_FILE);
-    let mut requirements_file = File::create(requirements_path)?;
-    debug_println!("DEBUG: Created requirements.txt file");
-    
     // Process dependencies from the raw file content
-    debug_println!("DEBUG: Processing dependencies from raw file content");
+    debug_println!("Processing dependencies from raw file content");
     
     // Find the dependencies section
     let mut in_dependencies = false;
     let mut in_pip = false;
+    let mut in_groups = false;
+    let mut current_group: Option<String> = None;
+    let mut pip_entries = env.pip.iter().flatten();
     
-    for line in file_content.lines() {
+    for (index, line) in file_content.lines().enumerate() {
+        let line_number = index + 1;
         let trimmed_line = line.trim();
         
         // Skip empty lines and comments at the beginning of lines
@@ -317,7 +2081,8 @@ async fn write_requirements_from_file(env_file: &str) -> Result<(), FondaError>
         if trimmed_line == "dependencies:" {
             in_dependencies = true;
             in_pip = false;
-            debug_println!("DEBUG: Found dependencies section");
+            in_groups = false;
+            trace_println!("Found dependencies section");
             continue;
         }
         
@@ -325,235 +2090,1646 @@ async fn write_requirements_from_file(env_file: &str) -> Result<(), FondaError>
         if trimmed_line == "pip:" {
             in_dependencies = false;
             in_pip = true;
-            debug_println!("DEBUG: Found pip section");
+            in_groups = false;
+            trace_println!("Found pip section");
             continue;
         }
-        
-        // If we're not in either section, skip
-        if !in_dependencies && !in_pip {
-            continue;
+        
+        // Inline values (`dependencies: [scipy]`, `groups: { dev: [black] }`) have no
+        // per-item lines or comments to scan, so their members come from the parsed file
+        let inline_value = |key: &str| {
+            trimmed_line.strip_prefix(key)
+                .map(str::trim)
+                .filter(|inline| !line.starts_with([' ', '\t']) && !inline.is_empty() && !inline.starts_with('#'))
+        };
+        if let Some(inline) = inline_value("dependencies:") {
+            in_dependencies = false;
+            in_pip = false;
+            in_groups = false;
+            trace_println!("Found inline dependencies section: {}", inline);
+            for dependency in &env.dependencies {
+                match dependency.strip_prefix("pip:") {
+                    Some(packages) => {
+                        let packages = packages.split(',').map(str::trim).filter(|package| !package.is_empty());
+                        for package_spec in packages {
+                            let mut requirement = Requirement::new(package_spec, source, line_number, "listed under dependencies");
+                            requirement.changes.push(format!("split out of `{}`", dependency));
+                            requirements.included.push(requirement);
+                        }
+                    }
+                    None => requirements.included.push(Requirement::new(dependency, source, line_number, "listed under dependencies")),
+                }
+            }
+            continue;
+        }
+        if let Some(inline) = inline_value("pip:") {
+            in_dependencies = false;
+            in_pip = false;
+            in_groups = false;
+            trace_println!("Found inline pip section: {}", inline);
+            for entry in env.pip.iter().flatten() {
+                let requirement = match entry {
+                    PipEntry::Spec(spec) => Requirement::new(spec, source, line_number, "listed under pip"),
+                    PipEntry::Package { name, index } => {
+                        let mut requirement = Requirement::new(name, source, line_number, "listed under pip");
+                        requirement.changes.push(pip_mapping_change(index.as_deref()));
+                        requirement
+                    }
+                };
+                requirements.included.push(requirement);
+            }
+            continue;
+        }
+        if let Some(inline) = inline_value("groups:") {
+            in_dependencies = false;
+            in_pip = false;
+            in_groups = false;
+            trace_println!("Found inline groups section: {}", inline);
+            for (name, specs) in env.groups.iter().flatten() {
+                let (reason, list) = if selected_groups.contains(name) {
+                    (format!("in selected group '{}'", name), &mut requirements.included)
+                } else {
+                    (format!("group '{}' not selected", name), &mut requirements.skipped)
+                };
+                for package_spec in specs {
+                    list.push(Requirement::new(package_spec, source, line_number, &reason));
+                }
+            }
+            continue;
+        }
+
+        // Check if we're entering the groups section
+        if trimmed_line == "groups:" {
+            in_dependencies = false;
+            in_pip = false;
+            in_groups = true;
+            current_group = None;
+            trace_println!("Found groups section");
+            continue;
+        }
+        
+        // If we're not in any section, skip
+        if !in_dependencies && !in_pip && !in_groups {
+            continue;
+        }
+        
+        // Check if we're exiting the current section (an unindented key)
+        if !trimmed_line.starts_with('-') && !line.starts_with([' ', '\t']) {
+            in_dependencies = false;
+            in_pip = false;
+            in_groups = false;
+            continue;
+        }
+        
+        // Group names such as `dev:` start a group inside the groups section
+        if in_groups && !trimmed_line.starts_with('-') {
+            if let Some((name, inline)) = trimmed_line.split_once(':') {
+                let name = name.trim().trim_matches(['"', '\'']).to_string();
+                trace_println!("Found dependency group: {}", name);
+                
+                // Flow lists (`dev: [black, mypy]`) have no per-item comments, so use the parsed values
+                let inline = inline.trim();
+                if !inline.is_empty() && !inline.starts_with('#') {
+                    for package_spec in env.groups.iter().flatten().filter(|(group, _)| **group == name).flat_map(|(_, specs)| specs) {
+                        if selected_groups.contains(&name) {
+                            trace_println!("Adding group '{}' dependency: {}", name, package_spec);
+                            let reason = format!("in selected group '{}'", name);
+                            requirements.included.push(Requirement::new(package_spec, source, line_number, &reason));
+                        } else {
+                            let reason = format!("group '{}' not selected", name);
+                            requirements.skipped.push(Requirement::new(package_spec, source, line_number, &reason));
+                        }
+                    }
+                }
+                current_group = Some(name);
+            }
+            continue;
+        }
+        
+        // Skip dependencies of groups that were not selected
+        if in_groups {
+            match &current_group {
+                Some(group) if selected_groups.contains(group) => {}
+                group => {
+                    trace_println!("SKIPPING dependency of unselected group {:?}: {}", group, trimmed_line);
+                    let dep_line = trimmed_line.trim_start_matches('-');
+                    let package_spec = dep_line.split('#').next().unwrap_or_default().trim();
+                    if trimmed_line.starts_with('-') && !package_spec.is_empty() {
+                        let mut changes = Vec::new();
+                        let package_spec = expand_raw_spec(package_spec, source, line_number, &mut changes)?;
+                        let reason = format!("group '{}' not selected", group.as_deref().unwrap_or_default());
+                        let mut requirement = Requirement::new(&package_spec, source, line_number, &reason);
+                        requirement.changes = changes;
+                        requirements.skipped.push(requirement);
+                    }
+                    continue;
+                }
+            }
+        }
+        
+        // Process dependency line
+        if trimmed_line.starts_with('-') {
+            let included_reason = match (&current_group, in_groups) {
+                (Some(group), true) => format!("in selected group '{}'", group),
+                _ if in_pip => "listed under pip".to_string(),
+                _ => "listed under dependencies".to_string(),
+            };
+            let dep_line = trimmed_line.trim_start_matches('-').trim();
+            trace_println!("Processing raw dependency line: '{}'", dep_line);
+            
+            // Mapping entries in the pip section (`{ name: ..., index: ... }`) use the parsed name
+            let mut mapped_spec = None;
+            let mut changes = Vec::new();
+            if in_pip {
+                if let Some(PipEntry::Package { name, index }) = pip_entries.next() {
+                    trace_println!("Pip mapping entry resolved to: '{}'", name);
+                    mapped_spec = Some(name.clone());
+                    changes.push(pip_mapping_change(index.as_deref()));
+                }
+            }
+            
+            // Handle pip: prefix in dependencies and groups sections
+            if (in_dependencies || in_groups) && dep_line.starts_with("pip:") {
+                let packages = dep_line.trim_start_matches("pip:").split(',');
+                for package in packages {
+                    let package_spec = package.trim();
+                    if !package_spec.is_empty() {
+                        let mut changes = vec![format!("split out of `{}`", dep_line)];
+                        let package_spec = expand_raw_spec(package_spec, source, line_number, &mut changes)?;
+                        trace_println!("Adding pip package from dependencies section: {}", package_spec);
+                        let mut requirement = Requirement::new(&package_spec, source, line_number, &included_reason);
+                        requirement.changes = changes;
+                        requirements.included.push(requirement);
+                    }
+                }
+                continue;
+            }
+            
+            let (raw_spec, comment) = match dep_line.find('#') {
+                Some(comment_idx) => (dep_line[..comment_idx].trim(), Some(dep_line[comment_idx..].trim())),
+                None => (dep_line, None),
+            };
+            let expanded_spec = match mapped_spec {
+                Some(name) => name,
+                None => expand_raw_spec(raw_spec, source, line_number, &mut changes)?,
+            };
+            let package_spec = expanded_spec.as_str();
+
+            // Check for platform-specific dependencies
+            if let Some(comment) = comment {
+                
+                trace_println!("Found comment in dependency: '{}'", comment);
+                trace_println!("Package spec: '{}'", package_spec);
+                
+                // Check if this is a platform-specific dependency
+                let comment_lower = comment.to_lowercase();
+                trace_println!("Comment lowercase: '{}'", comment_lower);
+                trace_println!("Target OS: '{}'", target_os);
+                
+                let section = if in_dependencies {
+                    "dependency"
+                } else if in_pip {
+                    "pip dependency"
+                } else {
+                    "group dependency"
+                };
+                trace_println!("PROCESSING - {}: {}, Comment: {}, Target OS: {}", section, package_spec, comment, target_os);
+                
+                // Skip Windows-only dependencies on non-Windows platforms
+                trace_println!("Checking for [win] marker: {}", comment_lower.contains("[win]"));
+                if comment_lower.contains("[win]") {
+                    trace_println!("FOUND Windows marker in: {}", comment);
+                    if target_os != "windows" {
+                        trace_println!("SKIPPING Windows-only {}: {}", section, package_spec);
+                        let mut requirement = Requirement::new(package_spec, source, line_number, "Windows only ([win])");
+                        requirement.selector = Some(format!("[win] does not match {}", target_os));
+                        requirement.changes = changes;
+                        requirements.skipped.push(requirement);
+                        continue;
+                    } else {
+                        trace_println!("KEEPING Windows-only {} (on Windows): {}", section, package_spec);
+                    }
+                }
+                
+                // Skip Linux-only dependencies on non-Linux platforms
+                trace_println!("Checking for [linux] marker: {}", comment_lower.contains("[linux]"));
+                if comment_lower.contains("[linux]") {
+                    trace_println!("FOUND Linux marker in: {}", comment);
+                    if target_os != "linux" {
+                        trace_println!("SKIPPING Linux-only {}: {}", section, package_spec);
+                        let mut requirement = Requirement::new(package_spec, source, line_number, "Linux only ([linux])");
+                        requirement.selector = Some(format!("[linux] does not match {}", target_os));
+                        requirement.changes = changes;
+                        requirements.skipped.push(requirement);
+                        continue;
+                    } else {
+                        trace_println!("KEEPING Linux-only {} (on Linux): {}", section, package_spec);
+                    }
+                }
+                
+                // Skip macOS-only dependencies on non-macOS platforms
+                trace_println!("Checking for [osx] marker: {}", comment_lower.contains("[osx]"));
+                trace_println!("Checking for [darwin] marker: {}", comment_lower.contains("[darwin]"));
+                if comment_lower.contains("[osx]") || comment_lower.contains("[darwin]") {
+                    trace_println!("FOUND macOS marker in: {}", comment);
+                    if target_os != "macos" {
+                        trace_println!("SKIPPING macOS-only {}: {}", section, package_spec);
+                        let mut requirement = Requirement::new(package_spec, source, line_number, "macOS only ([osx])");
+                        let marker = if comment_lower.contains("[osx]") { "[osx]" } else { "[darwin]" };
+                        requirement.selector = Some(format!("{} does not match {}", marker, target_os));
+                        requirement.changes = changes;
+                        requirements.skipped.push(requirement);
+                        continue;
+                    } else {
+                        trace_println!("KEEPING macOS-only {} (on macOS): {}", section, package_spec);
+                    }
+                }
+                
+                trace_println!("ADDING {} to requirements.txt: {}", section, package_spec);
+                
+                if !package_spec.is_empty() {
+                    let selector = ["[win]", "[linux]", "[osx]", "[darwin]"].into_iter()
+                        .find(|marker| comment_lower.contains(marker))
+                        .map(|marker| format!("{} matches {}", marker, target_os));
+                    let reason = match &selector {
+                        Some(selector) => format!("{}, {}", included_reason, selector),
+                        None => included_reason,
+                    };
+                    let mut requirement = Requirement::new(package_spec, source, line_number, &reason);
+                    requirement.selector = selector;
+                    requirement.changes = changes;
+                    requirements.included.push(requirement);
+                }
+            } else {
+                // No platform marker, include the dependency
+                if !package_spec.is_empty() {
+                    // Handle Git/URL dependencies and editable installs
+                    if package_spec.starts_with("git+") || 
+                       package_spec.starts_with("http://") || 
+                       package_spec.starts_with("https://") || 
+                       package_spec.starts_with("-e ") {
+                        trace_println!("Adding special dependency: {}", package_spec);
+                    } else {
+                        trace_println!("Adding regular dependency: {}", package_spec);
+                    }
+                    let mut requirement = Requirement::new(package_spec, source, line_number, &included_reason);
+                    requirement.changes = changes;
+                    requirements.included.push(requirement);
+                }
+            }
+        }
+}
+
+
+    Ok(requirements)
+}
+
+/// How a `pip:` mapping entry changes its spec, for `fonda why`
+fn pip_mapping_change(index: Option<&str>) -> String {
+    match index {
+        Some(index) => format!("pip mapping entry; its index {} is added as a package index", index),
+        None => "pip mapping entry".to_string(),
+    }
+}
+
+/// Expands `${VAR}` in a spec read from a raw line of `source`, noting the
+/// original text in `changes` when it differs
+///
+/// # Errors
+/// Returns `FondaError::UndefinedVariable` with the file and line of the spec.
+fn expand_raw_spec(spec: &str, source: &Path, line: usize, changes: &mut Vec<String>) -> Result<String, FondaError> {
+    let expanded = expand_template(spec)
+        .map_err(|err| FondaError::UndefinedVariable(format!("{} at {}:{}", err, display_source(source), line)))?;
+    if expanded != spec {
+        changes.push(format!("expanded from `{}`", spec));
+    }
+    Ok(expanded)
+}
+
+/// Reads and parses conda-style environment files (one per `-f`), merged with the
+/// files they include
+fn load_env_files(env_files: &[String]) -> Result<CondaEnv, FondaError> {
+    Ok(merge_env_chains(&load_env_chains(env_files)?))
+}
+
+/// Loads each environment file and everything it includes (via `include:` or `extends:`)
+///
+/// Returns one chain per file, each in merge order: included files first, in the
+/// order they are listed, then the including file. A file reached more than once
+/// within a chain is only loaded the first time; a file included by several `-f`
+/// files is part of each of their chains, so every chain sees its own overrides.
+///
+/// # Errors
+/// Returns `FondaError::IncludeCycle` if a file includes itself, directly or indirectly.
+fn load_env_chains(env_files: &[String]) -> Result<Vec<Vec<(PathBuf, CondaEnv)>>, FondaError> {
+    let mut chains = Vec::new();
+    for env_file in env_files {
+        let mut chain = Vec::new();
+        collect_env_chain(Path::new(env_file), None, &mut Vec::new(), &mut Vec::new(), &mut chain)?;
+        chains.push(chain);
+    }
+    Ok(chains)
+}
+
+fn collect_env_chain(
+    path: &Path,
+    included_from: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+    loaded: &mut Vec<PathBuf>,
+    chain: &mut Vec<(PathBuf, CondaEnv)>,
+) -> Result<(), FondaError> {
+    if !path.exists() {
+        return Err(FondaError::ConfigNotFound(match included_from {
+            Some(parent) => format!("{} not found (included from {})", path.display(), display_source(parent)),
+            None => format!("{} not found", path.display()),
+        }));
+    }
+
+    let canonical = canonical_path(path)?;
+    if let Some(start) = stack.iter().position(|entry| *entry == canonical) {
+        let cycle: Vec<String> = stack[start..].iter()
+            .chain(std::iter::once(&canonical))
+            .map(|entry| display_source(entry))
+            .collect();
+        return Err(FondaError::IncludeCycle(cycle.join(" -> ")));
+    }
+    if loaded.contains(&canonical) {
+        return Ok(());
+    }
+
+    let file = File::open(&canonical).with_context(|| format!("could not open {}", canonical.display()))?;
+    let mut env: CondaEnv = serde_yaml::from_reader(file)?;
+    expand_env_templates(&mut env, &canonical)?;
+
+    stack.push(canonical.clone());
+    let base_dir = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+    for include in env.include.iter().flat_map(OneOrMany::items) {
+        debug_println!("{} includes {}", display_source(&canonical), include);
+        collect_env_chain(&base_dir.join(include), Some(&canonical), stack, loaded, chain)?;
+    }
+    stack.pop();
+
+    loaded.push(canonical.clone());
+    chain.push((canonical, env));
+    Ok(())
+}
+
+/// Expands `${VAR}` references in the name and dependency specs of a parsed environment file
+///
+/// Every spec is expanded, including ones that platform markers or group selection
+/// will later skip, so an undefined variable is reported on every platform.
+///
+/// # Errors
+/// Returns `FondaError::UndefinedVariable` naming the file and the spec.
+fn expand_env_templates(env: &mut CondaEnv, source: &Path) -> Result<(), FondaError> {
+    let context = |field: &str, value: &str, err: String| {
+        FondaError::UndefinedVariable(format!("{} in {} '{}' ({})", err, field, value, display_source(source)))
+    };
+
+    env.name = expand_template(&env.name).map_err(|err| context("name", &env.name, err))?;
+    let specs = env.dependencies.iter_mut()
+        .chain(env.pip.iter_mut().flatten().map(PipEntry::spec_mut))
+        .chain(env.groups.iter_mut().flat_map(|groups| groups.values_mut()).flatten());
+    for spec in specs {
+        *spec = expand_template(spec).map_err(|err| context("dependency", spec, err))?;
+    }
+    Ok(())
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` from the process environment
+///
+/// The default is used when the variable is unset or empty. A `$` that does not
+/// start `${` is kept as is.
+///
+/// # Errors
+/// Returns a description of the first undefined variable (without a default) or
+/// unterminated `${`.
+fn expand_template(value: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| format!("unterminated '${{' in '{}'", value))?;
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        if name.is_empty() || name.contains(|c: char| !c.is_ascii_alphanumeric() && c != '_') {
+            return Err(format!("invalid variable name '{}' in '{}'", name, value));
+        }
+
+        match (std::env::var(name).ok().filter(|v| !v.is_empty()), default) {
+            (Some(v), _) => expanded.push_str(&v),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) if std::env::var_os(name).is_some() => {}
+            (None, None) => {
+                return Err(format!("${{{}}} is not set (use ${{{}:-default}} to give a fallback)", name, name));
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Merges the include chains of every `-f` file into one environment
+///
+/// Scalars (name, python_version, prefix, jupyter_kernel) follow last-wins,
+/// variables are merged with later values winning, and lists are concatenated
+/// in order (duplicate requirements are resolved by `collect_requirements`).
+fn merge_env_chains(chains: &[Vec<(PathBuf, CondaEnv)>]) -> CondaEnv {
+    let mut merged = CondaEnv::default();
+    let mut merged_files: Vec<&PathBuf> = Vec::new();
+    for (path, env) in chains.iter().flatten() {
+        // A file shared by several chains is merged once, where it first appears
+        if merged_files.contains(&path) {
+            continue;
+        }
+        merged_files.push(path);
+
+        if !env.name.is_empty() {
+            merged.name = env.name.clone();
+        }
+        merged.python_version = env.python_version.clone().or(merged.python_version);
+        merged.prefix = env.prefix.clone().or(merged.prefix);
+        merged.index_url = env.index_url.clone().or(merged.index_url);
+        merged.jupyter_kernel = env.jupyter_kernel.or(merged.jupyter_kernel);
+
+        for channel in env.channels.iter().flatten() {
+            let channels = merged.channels.get_or_insert_with(Vec::new);
+            if !channels.contains(channel) {
+                channels.push(channel.clone());
+            }
+        }
+        for (field, items) in [(&mut merged.find_links, &env.find_links), (&mut merged.trusted_host, &env.trusted_host)] {
+            let mut merged_items: Vec<String> = field.iter().flat_map(OneOrMany::items).cloned().collect();
+            for item in items.iter().flat_map(OneOrMany::items) {
+                if !merged_items.contains(item) {
+                    merged_items.push(item.clone());
+                }
+            }
+            if !merged_items.is_empty() {
+                *field = Some(OneOrMany::Many(merged_items));
+            }
+        }
+        if let Some(indexes) = &env.channel_indexes {
+            merged.channel_indexes.get_or_insert_with(BTreeMap::new).extend(indexes.clone());
+        }
+        merged.dependencies.extend(env.dependencies.iter().cloned());
+        if let Some(pip) = &env.pip {
+            merged.pip.get_or_insert_with(Vec::new).extend(pip.iter().cloned());
+        }
+        if let Some(variables) = &env.variables {
+            merged.variables.get_or_insert_with(BTreeMap::new).extend(variables.clone());
+        }
+        if let Some(hooks) = &env.post_create {
+            merged.post_create.get_or_insert_with(Vec::new).extend(hooks.iter().cloned());
+        }
+        if let Some(hooks) = &env.post_update {
+            merged.post_update.get_or_insert_with(Vec::new).extend(hooks.iter().cloned());
+        }
+        for (group, specs) in env.groups.iter().flatten() {
+            merged.groups
+                .get_or_insert_with(BTreeMap::new)
+                .entry(group.clone())
+                .or_default()
+                .extend(specs.iter().cloned());
+        }
+    }
+    merged
+}
+
+/// Path of an environment file for messages, relative to the current directory when possible
+fn display_source(path: &Path) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/// Validates an environment file and shows the combined result of its includes
+async fn check_env_file(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    let chains = load_env_chains(env_files)?;
+    let env = merge_env_chains(&chains);
+    let requirements = collect_requirements(&chains, &env, options)?;
+    let selected_groups = select_groups(&env, options)?;
+
+    let mut files: Vec<String> = Vec::new();
+    for file in chains.iter().flatten().map(|(path, _)| display_source(path)) {
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+    status_println!("Environment file: {}", env_files.join(", "));
+    if files.len() > 1 {
+        status_println!("Merged files:     {}", files.join(" -> "));
+    }
+    status_println!("Name:             {}", env.name);
+    status_println!("Python:           {}", env.python_version.as_deref().unwrap_or("(any)"));
+    let (pip_options, unmapped) = pip_options(&env);
+    if let Some(channels) = &env.channels {
+        status_println!("Channels:         {}", channels.join(", "));
+        if !unmapped.is_empty() {
+            status_println!("Ignored channels: {} (no package index)", unmapped.join(", "));
+        }
+    }
+    for (flag, value) in pip_options {
+        status_println!("Pip option:       {} {}", flag, value);
+    }
+    if let Some(groups) = &env.groups {
+        let names: Vec<&str> = groups.keys().map(String::as_str).collect();
+        status_println!("Groups:           {} (selected: {})", names.join(", "), selected_groups.join(", "));
+    }
+    for (name, value) in env_variables(&env)? {
+        status_println!("Variable:         {}={}", name, value);
+    }
+
+    status_println!("Requirements for {} ({}):", options.platform.as_deref().unwrap_or(OS), requirements.included.len());
+    for requirement in &requirements.included {
+        status_println!("  {:<40} {}", requirement.spec, requirement.location());
+        for replaced in &requirement.overrides {
+            status_println!("  {:<40}   overrides {}", "", replaced);
+        }
+    }
+    if !requirements.skipped.is_empty() {
+        status_println!("Skipped ({}):", requirements.skipped.len());
+        for requirement in &requirements.skipped {
+            status_println!("  {:<40} {} ({})", requirement.spec, requirement.location(), requirement.reason);
+        }
+    }
+    json_record_environment(|json_env| json_env.name = Some(env.name.clone()));
+    json_record_requirements(&requirements);
+
+    validate_env_name(&env.name)?;
+    status_println!("{} is valid.", env_files.join(", "));
+    Ok(())
+}
+
+/// Shows the state of the environment described by the YAML file
+///
+/// Reports where the environment lives, whether it exists and is registered, and
+/// whether requirements.txt matches what `fonda -w` would write now.
+///
+/// # Errors
+/// Returns `FondaError` if the environment file cannot be loaded.
+async fn show_status(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    let chains = load_env_chains(env_files)?;
+    let env = merge_env_chains(&chains);
+    validate_env_name(&env.name)?;
+
+    let venv_path = match &options.env_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => resolve_env_path(&env, options),
+    };
+    let exists = venv_python(&venv_path).exists();
+    let venv_path = if exists { canonical_path(&venv_path)? } else { venv_path };
+    let python_version = if exists { venv_python_version(&venv_path).await } else { None };
+    let registered = load_registry()?.environments.iter().any(|entry| entry.path == venv_path);
+
+    let requirements = collect_requirements(&chains, &env, options)?;
+    let requirements_status = match std::fs::read_to_string(REQUIREMENTS_FILE) {
+        Ok(content) if content == render_requirements(&env, &requirements) => "up_to_date",
+        Ok(_) => "out_of_date",
+        Err(_) => "missing",
+    };
+
+    status_println!("Environment:      {}", env.name);
+    status_println!("Path:             {}{}", display_env_path(&venv_path), if exists { "" } else { " (not created)" });
+    if let Some(version) = &python_version {
+        status_println!("Python:           {}", version);
+    }
+    status_println!("Registered:       {}", if registered { "yes" } else { "no" });
+    status_println!("{}: {}", REQUIREMENTS_FILE, match requirements_status {
+        "up_to_date" => "up to date",
+        "out_of_date" => "out of date (run `fonda -w` or `fonda update`)",
+        _ => "missing (run `fonda -w`)",
+    });
+
+    json_record_environment(|json_env| {
+        json_env.name = Some(env.name.clone());
+        json_env.path = Some(venv_path.clone());
+        json_env.exists = Some(exists);
+        json_env.python_version = python_version.clone();
+        json_env.registered = Some(registered);
+    });
+    json_record(|report| {
+        report.requirements_file = Some(REQUIREMENTS_FILE.to_string());
+        report.requirements_status = Some(requirements_status);
+    });
+    json_record_requirements(&requirements);
+    Ok(())
+}
+
+/// Explains why a package is or is not in requirements.txt (`fonda why <package>`)
+///
+/// Every line that mentions the package is traced to its file and line, with the
+/// platform marker and how it evaluated for the target platform (the current one,
+/// or `--platform`), the group or override that decided it, and any mapping or
+/// `${VAR}` expansion that changed the spec.
+fn explain_requirement(env_files: &[String], package: &str, options: &FondaOptions) -> Result<(), FondaError> {
+    let (_, requirements) = generate_requirements(env_files, options)?;
+    let key = package_key(package);
+    let target_os = options.platform.as_deref().unwrap_or(OS);
+
+    let matches: Vec<(&Requirement, bool)> = requirements.included.iter().map(|requirement| (requirement, true))
+        .chain(requirements.skipped.iter().map(|requirement| (requirement, false)))
+        .filter(|(requirement, _)| package_key(&requirement.spec) == key)
+        .collect();
+    if matches.is_empty() {
+        status_println!("{} is not mentioned in {} (or the files it includes).", package, env_files.join(", "));
+        return Ok(());
+    }
+
+    status_println!("Package {} on {}:", key, target_os);
+    for (requirement, included) in matches {
+        status_println!();
+        status_println!("{}: {}", requirement.spec, if included { "included in requirements.txt" } else { "skipped" });
+        status_println!("  Source:     {}", requirement.location());
+        if let Some(selector) = &requirement.selector {
+            status_println!("  Selector:   {}", selector);
+        }
+        status_println!("  Reason:     {}", requirement.reason);
+        for change in &requirement.changes {
+            status_println!("  Changed:    {}", change);
+        }
+        for replaced in &requirement.overrides {
+            status_println!("  Overrides:  {}", replaced);
+        }
+    }
+    Ok(())
+}
+
+/// The Python version of a virtual environment, from its interpreter
+async fn venv_python_version(venv_path: &Path) -> Option<String> {
+    match TokioCommand::new(venv_python(venv_path)).arg("--version").output().await {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            Some(version.trim().trim_start_matches("Python").trim().to_string())
+        }
+        _ => None,
+    }
+}
+
+async fn get_python_command() -> Result<&'static str, FondaError> {
+    for cmd in PYTHON_COMMANDS {
+        if let Ok(output) = TokioCommand::new(cmd)
+            .arg("--version")
+            .output()
+            .await
+        {
+            if output.status.success() {
+                return Ok(cmd);
+            }
+        }
+    }
+    Err(FondaError::PythonNotFound(format!("searched PATH for {}", PYTHON_COMMANDS.join(", "))))
+}
+
+/// Creates a new virtual environment and installs dependencies using the default environment file
+///
+/// # Errors
+/// Returns `FondaError` if:
+/// - The environment already exists
+/// - Python is not found
+/// - Virtual environment creation fails
+/// - Package installation fails
+async fn create_and_run(options: &FondaOptions) -> Result<(), FondaError> {
+    create_and_run_with_file(&[ENVIRONMENT_FILE.to_string()], options).await
+}
+
+/// Creates a new virtual environment and installs dependencies using a specified environment file
+///
+/// # Errors
+/// Returns `FondaError` if:
+/// - The environment already exists
+/// - Python is not found
+/// - Virtual environment creation fails
+/// - Package installation fails
+async fn create_and_run_with_file(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    // Read the .yaml files
+    let env = load_env_files(env_files)?;
+
+    // Generate requirements.txt using our platform-specific filtering
+    // We'll reuse the write_requirements_from_file function to ensure consistent behavior
+    write_requirements_from_file(env_files, options).await?;
+    
+    // Read the requirements.txt file that was just created
+    let requirements_path = Path::new(REQUIREMENTS_FILE);
+    if !requirements_path.exists() {
+        return Err(FondaError::RequirementsNotFound(format!("{} not found", REQUIREMENTS_FILE)));
+    }
+
+    // Create the virtual environment
+    let env_name = &env.name;
+    validate_env_name(env_name)?;
+
+    let venv_path = resolve_env_path(&env, options);
+    if venv_path.exists() {
+        if !options.force {
+            return Err(FondaError::EnvironmentExists(format!("{} ({})", env_name, venv_path.display())));
+        }
+        // Only replace directories that really are virtual environments
+        if !venv_path.join("pyvenv.cfg").exists() {
+            return Err(FondaError::CommandFailed {
+                command: "create --force".to_string(),
+                error: format!(
+                    "{} does not look like a virtual environment (no pyvenv.cfg), refusing to delete it",
+                    venv_path.display()
+                ),
+            });
+        }
+        info_println!("Removing existing environment '{}' at {} (--force)", env_name, venv_path.display());
+        std::fs::remove_dir_all(&venv_path).with_context(|| format!("could not remove {}", venv_path.display()))?;
+    }
+    let option_args = install_source_args(Some(&env), options)?;
+    if let Some(parent) = venv_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).with_context(|| format!("could not create directory {}", parent.display()))?;
+    }
+    debug_println!("Creating environment '{}' at {}", env_name, venv_path.display());
+    json_record_environment(|json_env| json_env.path = Some(venv_path.clone()));
+
+    // Try uv first, and fall back to python's venv module when uv is not installed
+    // or cannot create the environment. Any other error is reported as is.
+    // `--seed` installs pip, which uv leaves out by default and the installs below need.
+    let created_with_uv = match run_command("uv", &["venv", "--seed", sanitize_path(&venv_path)?]).await {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            warn_println!("uv venv failed ({}), falling back to python venv...", stderr.trim().lines().last().unwrap_or("no output"));
+            false
+        }
+        Err(FondaError::ToolNotInstalled(_)) => {
+            info_println!("uv not installed, falling back to python venv...");
+            false
+        }
+        Err(e) => return Err(e),
+    };
+
+    json_record_environment(|json_env| json_env.backend = Some(if created_with_uv { "uv" } else { "venv" }));
+    if created_with_uv {
+        info_println!("Environment created successfully using uv");
+    } else {
+        let python_command = get_python_command().await?;
+        let output = run_command(python_command, &["-m", "venv", sanitize_path(&venv_path)?]).await?;
+        if !output.status.success() {
+            return Err(FondaError::VenvCreationFailed(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        info_println!("Environment created successfully using python venv");
+    }
+    let created_path = canonical_path(&venv_path)?;
+    json_record_environment(|json_env| json_env.path = Some(created_path));
+
+    // Export the YAML variables from the activate scripts
+    let variables = env_variables(&env)?;
+    if !variables.is_empty() {
+        write_activation_variables(&venv_path, &variables)?;
+    }
+
+    // Install requirements using the new environment's pip
+    let mut pip_args = vec!["install", "-r", sanitize_path(requirements_path)?];
+    pip_args.extend(option_args.iter().map(String::as_str));
+    let (installer, install_args) = InstallTarget::Environment(venv_path.clone()).install_command(&pip_args, Some(&env)).await?;
+    let install_args: Vec<&str> = install_args.iter().map(String::as_str).collect();
+    let output = run_command(&installer, &install_args).await?;
+    if let Err(e) = check_pip_install(&output, options) {
+        warn_println!("Removing environment '{}' because its packages could not be installed", env_name);
+        std::fs::remove_dir_all(&venv_path).with_context(|| format!("could not remove {}", venv_path.display()))?;
+        return Err(e);
+    }
+
+    // Register a Jupyter kernel before the hooks, so they can rely on it
+    let jupyter_kernel = if options.jupyter_kernel || env.jupyter_kernel.unwrap_or(false) {
+        match install_jupyter_kernel(&canonical_path(&venv_path)?, env_name, &option_args, options).await {
+            Ok(kernel) => Some(kernel),
+            Err(e) => {
+                warn_println!("Removing environment '{}' because its Jupyter kernel could not be installed", env_name);
+                std::fs::remove_dir_all(&venv_path).with_context(|| format!("could not remove {}", venv_path.display()))?;
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Run the post-create hooks, removing the environment if a required one fails
+    if let Some(hooks) = &env.post_create {
+        let hook_venv_path = canonical_path(&venv_path)?;
+        if let Err(e) = run_hooks("post_create", hooks, &hook_venv_path, &variables).await {
+            warn_println!("Removing environment '{}' because a required post_create hook failed", env_name);
+            debug_println!("Removing {} after post_create failure", venv_path.display());
+            std::fs::remove_dir_all(&venv_path).with_context(|| format!("could not remove {}", venv_path.display()))?;
+            if let Some(kernel) = &jupyter_kernel {
+                remove_jupyter_kernel(kernel)?;
+            }
+            return Err(e);
+        }
+    }
+
+    // Record the environment so `fonda env` can find it again
+    if let Err(e) = register_environment(env_name, &venv_path, env_files, &env, jupyter_kernel.as_deref()).await {
+        warn_println!("Failed to record environment in registry: {}", e);
+    }
+
+    info_println!("Environment '{}' created and requirements installed successfully.", env_name);
+    info_println!("\nTo use your new environment:");
+    
+    if OS == "windows" {
+        info_println!("  Activate:   {}", display_env_path(&venv_path.join("Scripts").join("activate.bat")));
+        info_println!("  Deactivate: deactivate");
+    } else {
+        info_println!("  Activate:   source {}", display_env_path(&venv_path.join("bin").join("activate")));
+        info_println!("  Deactivate: deactivate");
+    }
+    info_println!("  Shell:      {}", fonda_hint("shell", env_files, options));
+    info_println!("  Run:        {} -- <command>", fonda_hint("run", env_files, options));
+    
+    info_println!("\nNote: You may need to restart your terminal for the environment to be available.");
+    Ok(())
+}
+
+/// An environment created by fonda, as recorded in the registry
+#[derive(Deserialize, Serialize, Clone)]
+struct RegistryEntry {
+    /// Name of the environment from the YAML file
+    name: String,
+    /// Absolute path of the virtual environment
+    path: PathBuf,
+    /// Absolute path of the YAML file the environment was created from
+    source: PathBuf,
+    /// Further YAML files merged in with additional `-f` flags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_sources: Vec<PathBuf>,
+    /// Python version of the environment's interpreter
+    python_version: String,
+    /// Creation time in seconds since the Unix epoch
+    created_at: u64,
+    /// Name of the Jupyter kernel registered for the environment, if any
+    #[serde(default)]
+    jupyter_kernel: Option<String>,
+}
+
+/// Every environment fonda has created, stored as JSON in the user's data dir
+#[derive(Deserialize, Serialize, Default)]
+struct Registry {
+    #[serde(default)]
+    environments: Vec<RegistryEntry>,
+}
+
+/// Per-user data directory for fonda (e.g. `~/.local/share/fonda` on Linux)
+fn user_data_dir() -> Result<PathBuf, FondaError> {
+    let base = match OS {
+        "windows" => std::env::var("APPDATA").map(PathBuf::from).ok(),
+        "macos" => std::env::var("HOME").map(|home| PathBuf::from(home).join("Library").join("Application Support")).ok(),
+        _ => std::env::var("XDG_DATA_HOME")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var("HOME").map(|home| PathBuf::from(home).join(".local").join("share")).ok()),
+    };
+
+    base.map(|dir| dir.join("fonda")).ok_or_else(|| {
+        FondaError::Io {
+            context: "could not determine the user data directory".to_string(),
+            error: io::Error::new(io::ErrorKind::NotFound, "HOME (APPDATA on Windows) is not set"),
+        }
+    })
+}
+
+/// Per-user state directory for fonda's logs (e.g. `~/.local/state/fonda` on Linux)
+fn user_state_dir() -> Result<PathBuf, FondaError> {
+    let base = match OS {
+        "windows" => std::env::var("LOCALAPPDATA").map(PathBuf::from).ok(),
+        "macos" => std::env::var("HOME").map(|home| PathBuf::from(home).join("Library").join("Logs")).ok(),
+        _ => std::env::var("XDG_STATE_HOME")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var("HOME").map(|home| PathBuf::from(home).join(".local").join("state")).ok()),
+    };
+
+    base.map(|dir| dir.join("fonda")).ok_or_else(|| {
+        FondaError::Io {
+            context: "could not determine the user state directory".to_string(),
+            error: io::Error::new(io::ErrorKind::NotFound, "HOME (LOCALAPPDATA on Windows) is not set"),
+        }
+    })
+}
+
+fn load_registry() -> Result<Registry, FondaError> {
+    let path = user_data_dir()?.join(REGISTRY_FILE);
+    if !path.exists() {
+        return Ok(Registry::default());
+    }
+
+    let content = std::fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))?;
+    serde_json::from_str(&content).map_err(|e| {
+        FondaError::Io {
+            context: format!("could not parse {}", path.display()),
+            error: io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    })
+}
+
+fn save_registry(registry: &Registry) -> Result<(), FondaError> {
+    let dir = user_data_dir()?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("could not create directory {}", dir.display()))?;
+
+    let content = serde_json::to_string_pretty(registry)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .with_context(|| "could not serialize the registry".to_string())?;
+    let registry_file = dir.join(REGISTRY_FILE);
+    std::fs::write(&registry_file, content).with_context(|| format!("could not write {}", registry_file.display()))?;
+    Ok(())
+}
+
+/// Records a newly created environment, replacing any stale entry at the same path
+async fn register_environment(
+    name: &str,
+    venv_path: &Path,
+    env_files: &[String],
+    env: &CondaEnv,
+    jupyter_kernel: Option<&str>,
+) -> Result<(), FondaError> {
+    let path = canonical_path(venv_path)?;
+    let mut sources = env_files.iter()
+        .map(|file| canonical_path(Path::new(file)))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    let source = sources.next().unwrap_or_default();
+    let extra_sources = sources.collect();
+
+    // Ask the environment's interpreter, falling back to the YAML requirement
+    let python_version = venv_python_version(&path).await
+        .or_else(|| env.python_version.clone())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let created_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    let mut registry = load_registry()?;
+    registry.environments.retain(|entry| entry.path != path);
+    registry.environments.push(RegistryEntry {
+        name: name.to_string(),
+        path,
+        source,
+        extra_sources,
+        python_version,
+        created_at,
+        jupyter_kernel: jupyter_kernel.map(str::to_string),
+    });
+    save_registry(&registry)?;
+
+    debug_println!("Registered environment '{}' at {}", name, venv_path.display());
+    Ok(())
+}
+
+/// Records the Jupyter kernel of a registered environment, so that `fonda env remove`
+/// removes it too
+///
+/// A different kernel recorded earlier (e.g. under an older naming scheme) is removed,
+/// since it belongs to the same environment.
+fn record_jupyter_kernel(venv_path: &Path, kernel: &str) -> Result<(), FondaError> {
+    let mut registry = load_registry()?;
+    let Some(entry) = registry.environments.iter_mut().find(|entry| entry.path == venv_path) else {
+        debug_println!("{} is not registered, not recording kernel '{}'", venv_path.display(), kernel);
+        return Ok(());
+    };
+    if entry.jupyter_kernel.as_deref() == Some(kernel) {
+        return Ok(());
+    }
+
+    if let Some(old_kernel) = entry.jupyter_kernel.replace(kernel.to_string()) {
+        remove_jupyter_kernel(&old_kernel)?;
+    }
+    save_registry(&registry)?;
+    debug_println!("Recorded Jupyter kernel '{}' for {}", kernel, venv_path.display());
+    Ok(())
+}
+
+/// Finds the registry entry matching a name or a path
+///
+/// # Errors
+/// Returns `FondaError::EnvironmentNotFound` if nothing matches, or if a name
+/// matches several environments (the error lists their paths).
+fn find_registry_entry(registry: &Registry, name_or_path: &str) -> Result<usize, FondaError> {
+    let canonical = std::fs::canonicalize(name_or_path).ok();
+    let matches: Vec<usize> = registry.environments.iter()
+        .enumerate()
+        .filter(|(_, entry)| {
+            entry.name == name_or_path
+                || entry.path == Path::new(name_or_path)
+                || canonical.as_ref() == Some(&entry.path)
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    match matches.as_slice() {
+        [index] => Ok(*index),
+        [] => Err(FondaError::EnvironmentNotFound(format!("'{}' is not in the registry", name_or_path))),
+        _ => {
+            let paths: Vec<String> = matches.iter()
+                .map(|&index| registry.environments[index].path.display().to_string())
+                .collect();
+            Err(FondaError::EnvironmentNotFound(format!(
+                "'{}' matches several environments, pass a path instead: {}",
+                name_or_path,
+                paths.join(", ")
+            )))
+        }
+    }
+}
+
+/// Runs `fonda env list`, `fonda env path`, `fonda env info` and `fonda env remove`
+async fn run_env_command(action: EnvAction, env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    match action {
+        EnvAction::Path => {
+            let venv_path = canonical_path(&resolve_existing_env(options, env_files)?)?;
+            println!("{}", venv_path.display());
+        }
+        EnvAction::List => {
+            let registry = load_registry()?;
+            if registry.environments.is_empty() {
+                println!("No environments registered.");
+                return Ok(());
+            }
+
+            println!("{:<24} {:<10} {:<20} PATH", "NAME", "PYTHON", "CREATED");
+            for entry in &registry.environments {
+                let missing = if entry.path.exists() { "" } else { " (missing)" };
+                println!(
+                    "{:<24} {:<10} {:<20} {}{}",
+                    entry.name,
+                    entry.python_version,
+                    format_timestamp(entry.created_at),
+                    entry.path.display(),
+                    missing
+                );
+            }
         }
-        
-        // Check if we're exiting the current section (indentation level change)
-        if !trimmed_line.starts_with('-') && !trimmed_line.starts_with(' ') {
-            in_dependencies = false;
-            in_pip = false;
-            continue;
+        EnvAction::Info(name) => {
+            let registry = load_registry()?;
+            let entry = &registry.environments[find_registry_entry(&registry, &name)?];
+            println!("Name:    {}", entry.name);
+            println!("Path:    {}", entry.path.display());
+            println!("Source:  {}", entry.source.display());
+            for extra_source in &entry.extra_sources {
+                println!("         + {}", extra_source.display());
+            }
+            println!("Python:  {}", entry.python_version);
+            println!("Created: {}", format_timestamp(entry.created_at));
+            if let Some(kernel) = &entry.jupyter_kernel {
+                println!("Kernel:  {}", kernel);
+            }
+            println!("Exists:  {}", if entry.path.exists() { "yes" } else { "no" });
         }
-        
-            // Process dependency line
-            if trimmed_line.starts_with('-') {
-                let dep_line = trimmed_line.trim_start_matches('-').trim();
-                debug_println!("DEBUG: Processing raw dependency line: '{}'", dep_line);
-                
-                // Handle pip: prefix in dependencies section
-                if in_dependencies && dep_line.starts_with("pip:") {
-                    let packages = dep_line.trim_start_matches("pip:").split(',');
-                    for package in packages {
-                        let package_spec = package.trim();
-                        if !package_spec.is_empty() {
-                            debug_println!("DEBUG: Adding pip package from dependencies section: {}", package_spec);
-                            writeln!(requirements_file, "{}", package_spec)?;
-                        }
-                    }
-                    continue;
-                }
-                
-                // Check for platform-specific dependencies
-                if let Some(comment_idx) = dep_line.find('#') {
-                    let package_spec = dep_line[0..comment_idx].trim();
-                    let comment = dep_line[comment_idx..].trim();
-                    
-                    debug_println!("DEBUG: Found comment in dependency: '{}'", comment);
-                    debug_println!("DEBUG: Package spec: '{}'", package_spec);
-                    
-                    // Check if this is a platform-specific dependency
-                    let comment_lower = comment.to_lowercase();
-                    debug_println!("DEBUG: Comment lowercase: '{}'", comment_lower);
-                    debug_println!("DEBUG: Current OS: '{}'", OS);
-                    
-                    let section = if in_dependencies { "dependency" } else { "pip dependency" };
-                    debug_println!("PROCESSING - {}: {}, Comment: {}, Current OS: {}", section, package_spec, comment, OS);
-                    
-                    // Skip Windows-only dependencies on non-Windows platforms
-                    debug_println!("DEBUG: Checking for [win] marker: {}", comment_lower.contains("[win]"));
-                    if comment_lower.contains("[win]") {
-                        debug_println!("FOUND Windows marker in: {}", comment);
-                        if OS != "windows" {
-                            debug_println!("SKIPPING Windows-only {}: {}", section, package_spec);
-                            continue;
-                        } else {
-                            debug_println!("KEEPING Windows-only {} (on Windows): {}", section, package_spec);
-                        }
-                    }
-                    
-                    // Skip Linux-only dependencies on non-Linux platforms
-                    debug_println!("DEBUG: Checking for [linux] marker: {}", comment_lower.contains("[linux]"));
-                    if comment_lower.contains("[linux]") {
-                        debug_println!("FOUND Linux marker in: {}", comment);
-                        if OS != "linux" {
-                            debug_println!("SKIPPING Linux-only {}: {}", section, package_spec);
-                            continue;
-                        } else {
-                            debug_println!("KEEPING Linux-only {} (on Linux): {}", section, package_spec);
-                        }
-                    }
-                    
-                    // Skip macOS-only dependencies on non-macOS platforms
-                    debug_println!("DEBUG: Checking for [osx] marker: {}", comment_lower.contains("[osx]"));
-                    debug_println!("DEBUG: Checking for [darwin] marker: {}", comment_lower.contains("[darwin]"));
-                    if comment_lower.contains("[osx]") || comment_lower.contains("[darwin]") {
-                        debug_println!("FOUND macOS marker in: {}", comment);
-                        if OS != "macos" {
-                            debug_println!("SKIPPING macOS-only {}: {}", section, package_spec);
-                            continue;
-                        } else {
-                            debug_println!("KEEPING macOS-only {} (on macOS): {}", section, package_spec);
-                        }
-                    }
-                    
-                    debug_println!("ADDING {} to requirements.txt: {}", section, package_spec);
-                    
-                    if !package_spec.is_empty() {
-                        writeln!(requirements_file, "{}", package_spec)?;
-                    }
-                } else {
-                    // No platform marker, include the dependency
-                    let package_spec = dep_line.trim();
-                    if !package_spec.is_empty() {
-                        // Handle Git/URL dependencies and editable installs
-                        if package_spec.starts_with("git+") || 
-                           package_spec.starts_with("http://") || 
-                           package_spec.starts_with("https://") || 
-                           package_spec.starts_with("-e ") {
-                            debug_println!("DEBUG: Adding special dependency: {}", package_spec);
-                            writeln!(requirements_file, "{}", package_spec)?;
-                        } else {
-                            debug_println!("DEBUG: Adding regular dependency: {}", package_spec);
-                            writeln!(requirements_file, "{}", package_spec)?;
-                        }
-                    }
+        EnvAction::Remove(name) => {
+            let mut registry = load_registry()?;
+            let entry = registry.environments.remove(find_registry_entry(&registry, &name)?);
+
+            if entry.path.exists() {
+                // Only delete directories that really are virtual environments
+                if !entry.path.join("pyvenv.cfg").exists() {
+                    return Err(FondaError::CommandFailed {
+                        command: "env remove".to_string(),
+                        error: format!(
+                            "{} does not look like a virtual environment (no pyvenv.cfg), refusing to delete it",
+                            entry.path.display()
+                        ),
+                    });
                 }
+                std::fs::remove_dir_all(&entry.path).with_context(|| format!("could not remove {}", entry.path.display()))?;
+                println!("Deleted {}", entry.path.display());
+            } else {
+                println!("{} no longer exists, removing it from the registry", entry.path.display());
+            }
+
+            if let Some(kernel) = &entry.jupyter_kernel {
+                remove_jupyter_kernel(kernel)?;
             }
+
+            save_registry(&registry)?;
+            println!("Environment '{}' removed.", entry.name);
+            debug_println!("Removed environment '{}' at {}", entry.name, entry.path.display());
+        }
     }
 
-    debug_println!("DEBUG: Finished processing all dependencies");
-    println!("requirements.txt created successfully.");
-    let _ = log_debug("requirements.txt created successfully.");
     Ok(())
 }
 
-async fn get_python_command() -> Result<&'static str, FondaError> {
-    for cmd in PYTHON_COMMANDS {
-        if let Ok(output) = TokioCommand::new(cmd)
-            .arg("--version")
-            .output()
-            .await
-        {
-            if output.status.success() {
-                return Ok(cmd);
+/// Per-user Jupyter kernels directory (`jupyter --data-dir` + `/kernels`)
+fn jupyter_kernels_dir() -> Result<PathBuf, FondaError> {
+    if let Ok(data_dir) = std::env::var("JUPYTER_DATA_DIR") {
+        if !data_dir.is_empty() {
+            return Ok(PathBuf::from(data_dir).join("kernels"));
+        }
+    }
+
+    let data_dir = match OS {
+        "windows" => std::env::var("APPDATA").map(|dir| PathBuf::from(dir).join("jupyter")).ok(),
+        "macos" => std::env::var("HOME").map(|home| PathBuf::from(home).join("Library").join("Jupyter")).ok(),
+        _ => std::env::var("XDG_DATA_HOME")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var("HOME").map(|home| PathBuf::from(home).join(".local").join("share")).ok())
+            .map(|dir| dir.join("jupyter")),
+    };
+
+    data_dir.map(|dir| dir.join("kernels")).ok_or_else(|| {
+        FondaError::Io {
+            context: "could not determine the Jupyter data directory".to_string(),
+            error: io::Error::new(io::ErrorKind::NotFound, "HOME (APPDATA on Windows) is not set"),
+        }
+    })
+}
+
+/// Installs ipykernel into an environment and registers it as a Jupyter kernel
+///
+/// The kernelspec is written directly rather than through `ipykernel install`,
+/// so only ipykernel itself has to be installable. Returns the kernel name:
+/// the environment name in lowercase plus a hash of the environment's path, so
+/// that same-named environments in different directories get their own kernels.
+/// `option_args` choose where ipykernel comes from (see `install_source_args`).
+///
+/// # Errors
+/// A failed ipykernel install is reported as by `check_pip_install`, so offline
+/// it names the wheelhouse.
+async fn install_jupyter_kernel(
+    venv_path: &Path,
+    env_name: &str,
+    option_args: &[String],
+    options: &FondaOptions,
+) -> Result<String, FondaError> {
+    let venv_path = &canonical_path(venv_path)?;
+    let python = venv_python(venv_path);
+    let mut pip_args = vec!["install", "ipykernel"];
+    pip_args.extend(option_args.iter().map(String::as_str));
+    let (pip, pip_args) = InstallTarget::Environment(venv_path.to_path_buf()).pip_command(&pip_args)?;
+    let output = run_command(&pip, &pip_args).await?;
+    check_pip_install(&output, options)?;
+
+    let kernel_name = format!("{}-{:08x}", env_name.to_lowercase(), path_hash(venv_path));
+    let kernel_dir = jupyter_kernels_dir()?.join(&kernel_name);
+    std::fs::create_dir_all(&kernel_dir).with_context(|| format!("could not create directory {}", kernel_dir.display()))?;
+
+    let kernelspec = serde_json::json!({
+        "argv": [python, "-m", "ipykernel_launcher", "-f", "{connection_file}"],
+        "display_name": format!("Python ({})", env_name),
+        "language": "python",
+        "metadata": { "debugger": true },
+    });
+    let content = serde_json::to_string_pretty(&kernelspec)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .with_context(|| "could not serialize the kernelspec".to_string())?;
+    let kernel_file = kernel_dir.join("kernel.json");
+    std::fs::write(&kernel_file, content).with_context(|| format!("could not write {}", kernel_file.display()))?;
+
+    info_println!("Registered Jupyter kernel '{}' at {}", kernel_name, kernel_dir.display());
+    Ok(kernel_name)
+}
+
+/// Removes a kernelspec written by `install_jupyter_kernel`
+fn remove_jupyter_kernel(kernel_name: &str) -> Result<(), FondaError> {
+    let kernel_dir = jupyter_kernels_dir()?.join(kernel_name);
+    if kernel_dir.join("kernel.json").exists() {
+        std::fs::remove_dir_all(&kernel_dir).with_context(|| format!("could not remove {}", kernel_dir.display()))?;
+        info_println!("Removed Jupyter kernel '{}'", kernel_name);
+    }
+    Ok(())
+}
+
+/// Shell function shared by the bash and zsh hooks. It looks for environment.yaml in
+/// the current directory or its parents, asks fonda where that environment lives,
+/// and swaps activation whenever the answer changes.
+const POSIX_HOOK: &str = r#"_fonda_hook() {
+  [ "$PWD" = "${_FONDA_HOOK_PWD:-}" ] && return
+  _FONDA_HOOK_PWD="$PWD"
+  local dir="$PWD" venv=""
+  while :; do
+    if [ -f "$dir/environment.yaml" ]; then
+      venv="$(cd "$dir" && "@FONDA@" env path 2>/dev/null)"
+      break
+    fi
+    [ "$dir" = "/" ] && break
+    dir="$(dirname "$dir")"
+  done
+  [ "$venv" = "${_FONDA_HOOK_ENV:-}" ] && return
+  if [ -n "${_FONDA_HOOK_ENV:-}" ] && [ "${VIRTUAL_ENV:-}" = "$_FONDA_HOOK_ENV" ] && type deactivate >/dev/null 2>&1; then
+    deactivate
+  fi
+  _FONDA_HOOK_ENV=""
+  if [ -n "$venv" ] && [ -f "$venv/bin/activate" ]; then
+    . "$venv/bin/activate"
+    _FONDA_HOOK_ENV="$venv"
+  fi
+}
+"#;
+
+const BASH_HOOK_REGISTER: &str = r#"case ";${PROMPT_COMMAND:-};" in
+  *";_fonda_hook;"*) ;;
+  *) PROMPT_COMMAND="_fonda_hook${PROMPT_COMMAND:+;$PROMPT_COMMAND}" ;;
+esac
+"#;
+
+const ZSH_HOOK_REGISTER: &str = r#"autoload -Uz add-zsh-hook
+add-zsh-hook chpwd _fonda_hook
+_fonda_hook
+"#;
+
+const FISH_HOOK: &str = r#"function _fonda_hook --on-variable PWD
+    set -l dir $PWD
+    set -l venv ""
+    while true
+        if test -f "$dir/environment.yaml"
+            set venv (sh -c 'cd "$1" && exec "$0" env path' "@FONDA@" "$dir" 2>/dev/null)
+            break
+        end
+        test "$dir" = "/"; and break
+        set dir (dirname "$dir")
+    end
+    test "$venv" = "$_fonda_hook_env"; and return
+    if test -n "$_fonda_hook_env"; and test "$VIRTUAL_ENV" = "$_fonda_hook_env"; and functions -q deactivate
+        deactivate
+    end
+    set -g _fonda_hook_env ""
+    if test -n "$venv"; and test -f "$venv/bin/activate.fish"
+        source "$venv/bin/activate.fish"
+        set -g _fonda_hook_env $venv
+    end
+end
+_fonda_hook
+"#;
+
+const DIRENV_ENVRC: &str = r#"# Generated by `fonda hook direnv`: use the fonda environment for environment.yaml
+watch_file environment.yaml
+if venv="$("@FONDA@" env path)"; then
+  export VIRTUAL_ENV="$venv"
+  PATH_add "$venv/bin"
+fi
+"#;
+
+/// Prints the shell snippet for `fonda hook <shell>`, or writes `.envrc` for direnv
+///
+/// The snippets call back into this fonda binary, so they use the same environment
+/// location rules as creation (see `resolve_env_path`).
+fn print_hook(shell: &str) -> Result<(), FondaError> {
+    let fonda = std::env::current_exe()
+        .map(|exe| exe.display().to_string())
+        .unwrap_or_else(|_| "fonda".to_string());
+
+    let snippet = match shell {
+        "bash" => format!("# Add to ~/.bashrc: eval \"$(fonda hook bash)\"\n{}{}", POSIX_HOOK, BASH_HOOK_REGISTER),
+        "zsh" => format!("# Add to ~/.zshrc: eval \"$(fonda hook zsh)\"\n{}{}", POSIX_HOOK, ZSH_HOOK_REGISTER),
+        "fish" => format!("# Add to ~/.config/fish/config.fish: fonda hook fish | source\n{}", FISH_HOOK),
+        "direnv" => {
+            let envrc = Path::new(".envrc");
+            if envrc.exists() {
+                return Err(FondaError::CommandFailed {
+                    command: "hook direnv".to_string(),
+                    error: ".envrc already exists, not overwriting it".to_string(),
+                });
             }
+            std::fs::write(envrc, DIRENV_ENVRC.replace("@FONDA@", &fonda))
+                .with_context(|| format!("could not write {}", envrc.display()))?;
+            println!(".envrc created. Run `direnv allow` to enable it.");
+            return Ok(());
+        }
+        _ => exit_with_usage(
+            &format!("unsupported shell '{}'", shell),
+            "Usage: fonda hook bash|zsh|fish|direnv",
+        ),
+    };
+
+    print!("{}", snippet.replace("@FONDA@", &fonda));
+    Ok(())
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS` (UTC)
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let time = secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, time / 3_600, (time % 3_600) / 60, time % 60
+    )
+}
+
+/// A fonda subcommand invocation that finds an environment again, for the final hints
+fn fonda_hint(subcommand: &str, env_files: &[String], options: &FondaOptions) -> String {
+    let mut hint = format!("fonda {}", subcommand);
+    if env_files != [ENVIRONMENT_FILE] {
+        for env_file in env_files {
+            hint.push_str(&format!(" -f {}", env_file));
         }
     }
-    Err(FondaError::PythonNotFound("No Python installation found".to_string()))
+    if let Some(prefix) = &options.prefix {
+        hint.push_str(&format!(" --prefix {}", prefix));
+    }
+    hint
 }
 
-/// Creates a new virtual environment and installs dependencies using the default environment file
+/// Updates an existing environment from its environment file
+///
+/// Regenerates requirements.txt, installs it into the environment, rewrites the
+/// activate script variables and runs the `post_update:` hooks.
 ///
 /// # Errors
-/// Returns `FondaError` if:
-/// - The environment already exists
-/// - Python is not found
-/// - Virtual environment creation fails
-/// - Package installation fails
-async fn create_and_run() -> Result<(), FondaError> {
-    create_and_run_with_file(ENVIRONMENT_FILE).await
+/// Returns `FondaError` if the environment does not exist, package installation
+/// fails or a required hook fails.
+async fn update_with_file(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    let env = load_env_files(env_files)?;
+    validate_env_name(&env.name)?;
+    let venv_path = canonical_path(&resolve_existing_env(options, env_files)?)?;
+    json_record_environment(|json_env| json_env.path = Some(venv_path.clone()));
+
+    write_requirements_from_file(env_files, options).await?;
+    run_requirements(
+        &[REQUIREMENTS_FILE.to_string()],
+        &InstallTarget::Environment(venv_path.clone()),
+        Some(&env),
+        options,
+    ).await?;
+
+    let variables = env_variables(&env)?;
+    write_activation_variables(&venv_path, &variables)?;
+
+    if options.jupyter_kernel || env.jupyter_kernel.unwrap_or(false) {
+        let kernel = install_jupyter_kernel(&venv_path, &env.name, &install_source_args(Some(&env), options)?, options).await?;
+        record_jupyter_kernel(&venv_path, &kernel)?;
+    }
+
+    if let Some(hooks) = &env.post_update {
+        run_hooks("post_update", hooks, &venv_path, &variables).await?;
+    }
+
+    info_println!("Environment '{}' updated successfully.", env.name);
+    Ok(())
 }
 
-/// Creates a new virtual environment and installs dependencies using a specified environment file
+/// Prints the requirements.txt that create or update would write, and what was skipped
+fn plan_requirements(env_files: &[String], options: &FondaOptions) -> Result<CondaEnv, FondaError> {
+    let (env, requirements) = generate_requirements(env_files, options)?;
+
+    status_println!("Would write {}:", REQUIREMENTS_FILE);
+    for line in render_requirements(&env, &requirements).lines() {
+        status_println!("  {}", line);
+    }
+    if !requirements.skipped.is_empty() {
+        status_println!("Skipped ({}):", requirements.skipped.len());
+        for requirement in &requirements.skipped {
+            status_println!("  {:<40} {} ({})", requirement.spec, requirement.location(), requirement.reason);
+        }
+    }
+
+    json_record_environment(|json_env| json_env.name = Some(env.name.clone()));
+    json_record(|report| report.requirements_file = Some(REQUIREMENTS_FILE.to_string()));
+    json_record_requirements(&requirements);
+    Ok(env)
+}
+
+/// Prints one command of a dry-run plan and records it for `--json`
+fn plan_command(command: &str, args: &[&str]) {
+    let line = command_line(command, args);
+    status_println!("  {}", line);
+    json_record(|report| report.planned_commands.push(line));
+}
+
+/// Prints the commands that install an environment's requirements, Jupyter kernel and hooks
+async fn plan_install_commands(
+    env: &CondaEnv,
+    venv_path: &Path,
+    hooks: Option<&[HookCommand]>,
+    options: &FondaOptions,
+) -> Result<(), FondaError> {
+    let option_args = install_source_args(Some(env), options)?;
+    let target = InstallTarget::Environment(venv_path.to_path_buf());
+
+    let mut pip_args = vec!["install", "-r", REQUIREMENTS_FILE];
+    pip_args.extend(option_args.iter().map(String::as_str));
+    let (installer, install_args) = target.install_command(&pip_args, Some(env)).await?;
+    plan_command(&installer, &install_args.iter().map(String::as_str).collect::<Vec<_>>());
+
+    if options.jupyter_kernel || env.jupyter_kernel.unwrap_or(false) {
+        let mut pip_args = vec!["install", "ipykernel"];
+        pip_args.extend(option_args.iter().map(String::as_str));
+        let (pip, pip_args) = target.pip_command(&pip_args)?;
+        plan_command(&pip, &pip_args);
+    }
+
+    let (shell, shell_flag) = hook_shell();
+    for hook in hooks.unwrap_or_default() {
+        plan_command(shell, &[shell_flag, hook.command()]);
+    }
+    Ok(())
+}
+
+/// Shows what `fonda create --dry-run` would do: the requirements, the Python and
+/// backend that would create the environment, where, and every command it would run.
+/// Nothing is written, created or removed.
 ///
 /// # Errors
-/// Returns `FondaError` if:
-/// - The environment already exists
-/// - Python is not found
-/// - Virtual environment creation fails
-/// - Package installation fails
-async fn create_and_run_with_file(env_file: &str) -> Result<(), FondaError> {
-    // Read the .yaml file
-    let path = Path::new(env_file);
-    if !path.exists() {
-        return Err(FondaError::ConfigNotFound(format!("{} not found", env_file)));
+/// Returns the same errors the real create would fail with before running anything,
+/// e.g. `FondaError::EnvironmentExists` without `--force`.
+async fn plan_create(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    let env = plan_requirements(env_files, options)?;
+    validate_env_name(&env.name)?;
+
+    let venv_path = resolve_env_path(&env, options);
+    let exists = venv_path.exists();
+    if exists {
+        if !options.force {
+            return Err(FondaError::EnvironmentExists(format!("{} ({})", env.name, venv_path.display())));
+        }
+        if !venv_path.join("pyvenv.cfg").exists() {
+            return Err(FondaError::CommandFailed {
+                command: "create --force".to_string(),
+                error: format!(
+                    "{} does not look like a virtual environment (no pyvenv.cfg), refusing to delete it",
+                    venv_path.display()
+                ),
+            });
+        }
     }
 
-    // First, parse the YAML file to get the basic structure (for validation)
-    let file = File::open(path)?;
-    let env: CondaEnv = serde_yaml::from_reader(file)?;
+    // Probe the tools the same way create does, without creating anything
+    let uv_available = uv_installed().await;
+    let python_command = get_python_command().await;
 
-    // Generate requirements.txt using our platform-specific filtering
-    // We'll reuse the write_requirements_from_file function to ensure consistent behavior
-    write_requirements_from_file(env_file).await?;
-    
-    // Read the requirements.txt file that was just created
-    let requirements_path = Path::new(REQUIREMENTS_FILE);
-    if !requirements_path.exists() {
-        return Err(FondaError::RequirementsNotFound(format!("{} not found", REQUIREMENTS_FILE)));
+    status_println!("Environment:      {}", env.name);
+    status_println!("Path:             {}{}", display_env_path(&venv_path),
+        if exists { " (exists, --force would remove it first)" } else { "" });
+    status_println!("Python:           {}", python_command.as_deref().unwrap_or("not found"));
+    if uv_available {
+        status_println!("Backend:          uv (python venv if `uv venv` fails)");
+    } else {
+        status_println!("Backend:          python venv (uv not installed)");
+    }
+    let variables = env_variables(&env)?;
+    if !variables.is_empty() {
+        status_println!("Variables:        {} exported from the activate scripts", variables.len());
     }
+    json_record_environment(|json_env| {
+        json_env.path = Some(venv_path.clone());
+        json_env.exists = Some(exists);
+        json_env.backend = Some(if uv_available { "uv" } else { "venv" });
+    });
 
-    // Create the virtual environment
-    let env_name = &env.name;
-    validate_env_name(env_name)?;
+    status_println!("Commands:");
+    let venv_arg = sanitize_path(&venv_path)?;
+    if uv_available {
+        plan_command("uv", &["venv", "--seed", venv_arg]);
+    } else {
+        plan_command(python_command?, &["-m", "venv", venv_arg]);
+    }
+    plan_install_commands(&env, &venv_path, env.post_create.as_deref(), options).await?;
 
-    let venv_path = PathBuf::from(env_name);
-    if venv_path.exists() {
-        return Err(FondaError::EnvironmentExists(env_name.clone()));
-    }
-
-    // Try uv first, fall back to pip if not available
-    let env_creation_result = match run_command("uv", &["venv", sanitize_path(&venv_path)?]).await {
-        Ok(_) => {
-            println!("Environment created successfully using uv");
-            Ok(())
-        }
-        Err(_) => {
-            println!("uv not found or failed, falling back to python venv...");
-            let python_command = get_python_command().await?;
-            match run_command(
-                python_command,
-                &["-m", "venv", sanitize_path(&venv_path)?]
-            ).await {
-                Ok(_) => {
-                    println!("Environment created successfully using python venv");
-                    Ok(())
-                }
-                Err(e) => Err(FondaError::VenvCreationFailed(e.to_string()))
-            }
+    status_println!("Dry run: nothing was written, created or installed.");
+    Ok(())
+}
+
+/// Shows what `fonda update --dry-run` would do, without writing or installing anything
+///
+/// # Errors
+/// Returns `FondaError::EnvironmentNotFound` if there is no environment to update.
+async fn plan_update(env_files: &[String], options: &FondaOptions) -> Result<(), FondaError> {
+    validate_env_name(&load_env_files(env_files)?.name)?;
+    let venv_path = canonical_path(&resolve_existing_env(options, env_files)?)?;
+    let env = plan_requirements(env_files, options)?;
+
+    status_println!("Environment:      {}", env.name);
+    status_println!("Path:             {}", display_env_path(&venv_path));
+    let variables = env_variables(&env)?;
+    if !variables.is_empty() {
+        status_println!("Variables:        {} exported from the activate scripts", variables.len());
+    }
+    json_record_environment(|json_env| json_env.path = Some(venv_path.clone()));
+
+    status_println!("Commands:");
+    plan_install_commands(&env, &venv_path, env.post_update.as_deref(), options).await?;
+
+    status_println!("Dry run: nothing was written or installed.");
+    Ok(())
+}
+
+/// Shows the pip command `fonda -r --dry-run` would run, without running it
+///
+/// # Errors
+/// Returns `FondaError::RequirementsNotFound` if any of the files do not exist.
+fn plan_run_requirements(files: &[String], target: &InstallTarget, options: &FondaOptions) -> Result<(), FondaError> {
+    let missing: Vec<&str> = files.iter()
+        .map(String::as_str)
+        .filter(|file| !Path::new(file).exists())
+        .collect();
+    if !missing.is_empty() {
+        return Err(FondaError::RequirementsNotFound(format!("{} not found", missing.join(", "))));
+    }
+    let option_args = install_source_args(None, options)?;
+
+    match target {
+        InstallTarget::Environment(venv_path) => {
+            let path = std::fs::canonicalize(venv_path).unwrap_or_else(|_| venv_path.clone());
+            status_println!("Environment:      {}", display_env_path(&path));
+            json_record_environment(|json_env| json_env.path = Some(path));
         }
-    };
+        InstallTarget::System => status_println!("Environment:      system Python (--system)"),
+    }
 
-    env_creation_result?;
+    let mut pip_args = vec!["install"];
+    for file in files {
+        pip_args.push("-r");
+        pip_args.push(sanitize_path(Path::new(file))?);
+    }
+    pip_args.extend(option_args.iter().map(String::as_str));
+    let (pip, pip_args) = target.pip_command(&pip_args)?;
 
-    // Install requirements using pip
-    let python_cmd = get_python_command().await?;
-    run_command(
-        python_cmd,
-        &["-m", "pip", "install", "-r", sanitize_path(requirements_path)?]
-    ).await?;
+    status_println!("Commands:");
+    plan_command(&pip, &pip_args);
+    status_println!("Dry run: nothing was installed.");
+    Ok(())
+}
 
-    println!("Environment '{}' created and requirements installed successfully.", env_name);
-    println!("\nTo use your new environment:");
-    
-    if OS == "windows" {
-        println!("  Activate:   .\\{}\\Scripts\\activate.bat", env_name);
-        println!("  Deactivate: deactivate");
+/// Runs `post_create:` or `post_update:` hook commands inside an environment
+///
+/// Each command runs through the platform shell with the environment's variables
+/// (see `environment_vars`) and its result is reported individually. Failed
+/// optional hooks only warn.
+///
+/// # Errors
+/// Returns `FondaError::CommandFailed` for the first required hook that fails;
+/// the remaining hooks are not run.
+async fn run_hooks(
+    stage: &str,
+    hooks: &[HookCommand],
+    venv_path: &Path,
+    variables: &[(String, String)],
+) -> Result<(), FondaError> {
+    let envs = environment_vars(venv_path, variables)?;
+    let (shell, shell_flag) = hook_shell();
+
+    info_println!("Running {} {} hook(s)...", hooks.len(), stage);
+    let mut failed_optional = 0;
+    for hook in hooks {
+        let failure = match run_command_with_env(shell, &[shell_flag, hook.command()], &envs).await {
+            Ok(output) if output.status.success() => {
+                debug_println!("{} output:\n{}", hook.command(), String::from_utf8_lossy(&output.stdout));
+                None
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if !stderr.trim().is_empty() {
+                    eprintln!("{}", stderr.trim_end());
+                }
+                Some(format!("exited with {}", output.status))
+            }
+            Err(e) => Some(e.to_string()),
+        };
+
+        match failure {
+            None => info_println!("  {} hook succeeded: {}", stage, hook.command()),
+            Some(error) if hook.required() => {
+                info_println!("  {} hook FAILED: {} ({})", stage, hook.command(), error);
+                return Err(FondaError::CommandFailed {
+                    command: hook.command().to_string(),
+                    error,
+                });
+            }
+            Some(error) => {
+                info_println!("  {} hook failed (optional, continuing): {} ({})", stage, hook.command(), error);
+                failed_optional += 1;
+            }
+        }
+    }
+
+    if failed_optional > 0 {
+        info_println!("{} hooks finished, {} optional hook(s) failed.", stage, failed_optional);
     } else {
-        println!("  Activate:   source ./{}/bin/activate", env_name);
-        println!("  Deactivate: deactivate");
+        info_println!("All {} hooks succeeded.", stage);
     }
-    
-    println!("\nNote: You may need to restart your terminal for the environment to be available.");
     Ok(())
 }
 
+/// The shell hook commands run through, with its flag for running one command
+fn hook_shell() -> (&'static str, &'static str) {
+    if OS == "windows" { ("cmd", "/C") } else { ("sh", "-c") }
+}
+
 fn sanitize_path(path: &Path) -> Result<&str, FondaError> {
     path.to_str().ok_or_else(|| FondaError::CommandFailed {
         command: "path conversion".to_string(),
@@ -563,10 +3739,281 @@ fn sanitize_path(path: &Path) -> Result<&str, FondaError> {
 
 fn validate_env_name(name: &str) -> Result<(), FondaError> {
     if name.is_empty() || name.contains(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-') {
-        return Err(FondaError::CommandFailed {
-            command: "validate_env_name".to_string(),
-            error: "Environment name must only contain alphanumeric characters, underscores, or hyphens".to_string(),
-        });
+        return Err(FondaError::InvalidConfig(format!(
+            "environment name '{}' must only contain alphanumeric characters, underscores, or hyphens", name
+        )));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `content` as an environment file named environment.yaml and scans it for `target_os`
+    fn scan(content: &str, selected_groups: &[&str], target_os: &str) -> Requirements {
+        let env: CondaEnv = serde_yaml::from_str(content).unwrap();
+        let selected_groups: Vec<String> = selected_groups.iter().map(|group| group.to_string()).collect();
+        scan_requirements(Path::new("environment.yaml"), content, &env, &selected_groups, target_os).unwrap()
+    }
+
+    fn specs(requirements: &[Requirement]) -> Vec<&str> {
+        requirements.iter().map(|requirement| requirement.spec.as_str()).collect()
+    }
+
+    #[test]
+    fn scan_requirements_reads_inline_groups() {
+        let requirements = scan(
+            "name: demo\ndependencies:\n  - requests\ngroups: { dev: [black, mypy], test: [pytest] }\n",
+            &["dev"],
+            "linux",
+        );
+
+        assert_eq!(specs(&requirements.included), ["requests", "black", "mypy"]);
+        assert_eq!(specs(&requirements.skipped), ["pytest"]);
+        assert_eq!(requirements.included[1].reason, "in selected group 'dev'");
+        assert_eq!(requirements.skipped[0].reason, "group 'test' not selected");
+        assert_eq!(requirements.skipped[0].line, 4);
+    }
+
+    #[test]
+    fn scan_requirements_reads_inline_dependencies_and_pip() {
+        let requirements = scan(
+            "name: demo\ndependencies: [numpy, scipy]\n\
+             pip: [requests, { name: private-lib, index: https://pkgs.example.com/simple }]\n",
+            &[],
+            "linux",
+        );
+
+        assert_eq!(specs(&requirements.included), ["numpy", "scipy", "requests", "private-lib"]);
+        assert!(requirements.skipped.is_empty());
+        assert_eq!(requirements.included[1].line, 2);
+        assert_eq!(requirements.included[2].reason, "listed under pip");
+        assert_eq!(requirements.included[3].changes, [pip_mapping_change(Some("https://pkgs.example.com/simple"))]);
+    }
+
+    #[test]
+    fn scan_requirements_applies_platform_markers() {
+        let content = "name: demo\ndependencies:\n  - numpy\n  - pywin32  # [win]\n  - python-xlib  # [linux]\n\
+                       pip:\n  - requests\n  - pyobjc  # [osx]\n";
+
+        let linux = scan(content, &[], "linux");
+        assert_eq!(specs(&linux.included), ["numpy", "python-xlib", "requests"]);
+        assert_eq!(specs(&linux.skipped), ["pywin32", "pyobjc"]);
+        assert_eq!(linux.skipped[0].line, 4);
+
+        let windows = scan(content, &[], "windows");
+        assert_eq!(specs(&windows.included), ["numpy", "pywin32", "requests"]);
+    }
+
+    #[test]
+    fn collect_chain_requirements_expands_each_spec_once() {
+        std::env::set_var("FONDA_TEST_EXPAND_ONCE", "1.0+${literal}");
+        let dir = create_private_temp_dir("fonda-test-expand").unwrap();
+        let file = dir.join("environment.yaml");
+        std::fs::write(
+            &file,
+            "name: demo\ndependencies:\n  - numpy==${FONDA_TEST_EXPAND_ONCE}  # [linux]\n\
+             pip:\n  - { name: 'lib==${FONDA_TEST_EXPAND_ONCE}', index: https://pkgs.example.com/simple }\n\
+             groups:\n  dev: ['black==${FONDA_TEST_EXPAND_ONCE}']\n",
+        ).unwrap();
+
+        let chains = load_env_chains(&[file.to_string_lossy().into_owned()]).unwrap();
+        let requirements = collect_chain_requirements(&chains[0], &["dev".to_string()], "linux").unwrap();
+        assert_eq!(specs(&requirements.included), ["numpy==1.0+${literal}", "lib==1.0+${literal}", "black==1.0+${literal}"]);
+        assert_eq!(requirements.included[0].changes, ["expanded from `numpy==${FONDA_TEST_EXPAND_ONCE}`"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn collect_requirements_gives_each_chain_its_shared_includes() {
+        let dir = create_private_temp_dir("fonda-test-chains").unwrap();
+        std::fs::write(dir.join("base.yaml"), "dependencies:\n  - numpy==1.0\n  - requests\n").unwrap();
+        std::fs::write(dir.join("a.yaml"), "name: a\ninclude: base.yaml\ndependencies:\n  - pandas\n").unwrap();
+        std::fs::write(dir.join("b.yaml"), "name: b\ninclude: base.yaml\ndependencies:\n  - numpy==3.0\n").unwrap();
+        std::fs::write(dir.join("c.yaml"), "name: c\ninclude: base.yaml\ndependencies:\n  - scipy\n").unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let collect = |files: &[&str]| {
+            let chains = load_env_chains(&files.iter().map(|file| path(file)).collect::<Vec<_>>()).unwrap();
+            collect_requirements(&chains, &merge_env_chains(&chains), &FondaOptions::default())
+        };
+
+        // The shared base gives both chains the same specs, which are unioned once
+        let requirements = collect(&["a.yaml", "c.yaml"]).unwrap();
+        assert_eq!(specs(&requirements.included), ["numpy==1.0", "requests", "pandas", "scipy"]);
+        assert_eq!(specs(&collect(&["c.yaml", "a.yaml"]).unwrap().included), ["numpy==1.0", "requests", "scipy", "pandas"]);
+        assert_eq!(merge_env_chains(&load_env_chains(&[path("a.yaml"), path("c.yaml")]).unwrap()).dependencies.len(), 4);
+
+        // b overrides the base only in its own chain, so a still asks for numpy==1.0 in either order
+        for files in [["a.yaml", "b.yaml"], ["b.yaml", "a.yaml"]] {
+            match collect(&files) {
+                Err(FondaError::DependencyConflict(message)) => assert!(message.contains("numpy==3.0"), "{}", message),
+                other => panic!("expected DependencyConflict for {:?}, got {:?}", files, other),
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pip_options_maps_channels_and_package_indexes() {
+        let env: CondaEnv = serde_yaml::from_str(
+            "name: demo\nchannels: [conda-forge, pytorch, internal]\n\
+             channel_indexes: { internal: https://pkgs.example.com/simple }\n\
+             pip:\n  - { name: private-lib, index: https://private.example.com/simple }\n\
+             trusted_host: pkgs.example.com\n",
+        ).unwrap();
+
+        let (options, unmapped) = pip_options(&env);
+        assert_eq!(options, [
+            ("--extra-index-url", "https://download.pytorch.org/whl/cpu".to_string()),
+            ("--extra-index-url", "https://pkgs.example.com/simple".to_string()),
+            ("--extra-index-url", "https://private.example.com/simple".to_string()),
+            ("--trusted-host", "pkgs.example.com".to_string()),
+        ]);
+        assert_eq!(unmapped, ["conda-forge"]);
+    }
+
+    #[test]
+    fn pip_options_uses_pypi_channel_or_index_url_as_main_index() {
+        let env: CondaEnv = serde_yaml::from_str("name: demo\nchannels: [pypi, pytorch]\n").unwrap();
+        assert_eq!(pip_options(&env).0, [
+            ("--index-url", PYPI_INDEX.to_string()),
+            ("--extra-index-url", "https://download.pytorch.org/whl/cpu".to_string()),
+        ]);
+
+        let env: CondaEnv = serde_yaml::from_str(
+            "name: demo\nchannels: [pypi]\nindex_url: https://mirror.example.com/simple\n",
+        ).unwrap();
+        assert_eq!(pip_options(&env).0, [
+            ("--index-url", "https://mirror.example.com/simple".to_string()),
+            ("--extra-index-url", PYPI_INDEX.to_string()),
+        ]);
+    }
+
+    #[test]
+    fn install_source_args_uses_only_the_wheelhouse_offline() {
+        let wheelhouse = create_private_temp_dir("fonda-test-wheelhouse").unwrap();
+        let env: CondaEnv = serde_yaml::from_str("name: demo\nchannels: [pytorch]\n").unwrap();
+        let mut options = FondaOptions {
+            offline: true,
+            wheelhouse: Some(wheelhouse.to_string_lossy().into_owned()),
+            ..FondaOptions::default()
+        };
+
+        let args = install_source_args(Some(&env), &options).unwrap();
+        assert_eq!(args, ["--no-index", "--find-links", sanitize_path(&wheelhouse).unwrap()]);
+
+        options.offline = false;
+        let args = install_source_args(Some(&env), &options).unwrap();
+        assert_eq!(args, [
+            "--extra-index-url", "https://download.pytorch.org/whl/cpu",
+            "--find-links", sanitize_path(&wheelhouse).unwrap(),
+        ]);
+
+        std::fs::remove_dir(&wheelhouse).unwrap();
+        options.offline = true;
+        assert!(matches!(install_source_args(Some(&env), &options), Err(FondaError::OfflineInstallFailed(_))));
+    }
+
+    #[cfg(unix)]
+    fn pip_output(code: i32, stderr: &str) -> std::process::Output {
+        use std::os::unix::process::ExitStatusExt;
+        std::process::Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_pip_install_reports_failures() {
+        let stderr = "ERROR: Could not find a version that satisfies the requirement missing-pkg\n\
+                      ERROR: No matching distribution found for missing-pkg\n";
+        let mut options = FondaOptions { wheelhouse: Some("wheels".to_string()), ..FondaOptions::default() };
+
+        assert!(check_pip_install(&pip_output(0, ""), &options).is_ok());
+        match check_pip_install(&pip_output(1, stderr), &options) {
+            Err(FondaError::CommandFailed { command, error }) => {
+                assert_eq!(command, "pip install");
+                assert_eq!(error, "ERROR: No matching distribution found for missing-pkg");
+            }
+            other => panic!("expected CommandFailed, got {:?}", other),
+        }
+
+        options.offline = true;
+        match check_pip_install(&pip_output(1, stderr), &options) {
+            Err(FondaError::OfflineInstallFailed(message)) => assert!(message.starts_with("not in wheelhouse wheels: missing-pkg.")),
+            other => panic!("expected OfflineInstallFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn expand_template_substitutes_variables_and_defaults() {
+        std::env::set_var("FONDA_TEST_EXPAND_SET", "2.1");
+        std::env::remove_var("FONDA_TEST_EXPAND_UNSET");
+
+        assert_eq!(expand_template("torch==${FONDA_TEST_EXPAND_SET}").unwrap(), "torch==2.1");
+        assert_eq!(expand_template("torch==${FONDA_TEST_EXPAND_UNSET:-2.0}").unwrap(), "torch==2.0");
+        assert_eq!(expand_template("price$5").unwrap(), "price$5");
+        assert!(expand_template("torch==${FONDA_TEST_EXPAND_UNSET}").unwrap_err().contains("is not set"));
+        assert!(expand_template("torch==${FONDA_TEST_EXPAND_SET").unwrap_err().starts_with("unterminated"));
+        assert!(expand_template("${BAD-NAME}").unwrap_err().starts_with("invalid variable name"));
+    }
+
+    #[test]
+    fn merge_env_chains_lets_later_files_win() {
+        let base: CondaEnv = serde_yaml::from_str(
+            "name: base\npython_version: '3.10'\nchannels: [pypi]\ndependencies: [numpy]\n\
+             variables: { MODE: base, LEVEL: 1 }\n",
+        ).unwrap();
+        let child: CondaEnv = serde_yaml::from_str(
+            "name: child\nchannels: [pypi, pytorch]\ndependencies: [torch]\nvariables: { MODE: child }\n",
+        ).unwrap();
+
+        let merged = merge_env_chains(&[vec![(PathBuf::from("base.yaml"), base), (PathBuf::from("child.yaml"), child)]]);
+        assert_eq!(merged.name, "child");
+        assert_eq!(merged.python_version.as_deref(), Some("3.10"));
+        assert_eq!(merged.channels.unwrap(), ["pypi", "pytorch"]);
+        assert_eq!(merged.dependencies, ["numpy", "torch"]);
+        let variables = merged.variables.unwrap();
+        assert_eq!(variables["MODE"], serde_yaml::Value::from("child"));
+        assert_eq!(variables["LEVEL"], serde_yaml::Value::from(1));
+    }
+
+    #[test]
+    fn package_key_normalizes_names() {
+        assert_eq!(package_key("Typing_Extensions>=4.0"), "typing-extensions");
+        assert_eq!(package_key("zope.interface==6.0"), "zope-interface");
+        assert_eq!(package_key("requests[socks] ; python_version < '3.12'"), "requests");
+        assert_eq!(package_key("-e ./local"), "-e ./local");
+        assert_eq!(package_key("git+https://example.com/repo.git"), "git+https://example.com/repo.git");
+    }
+
+    #[test]
+    fn rotate_log_shifts_old_logs_and_drops_the_oldest() {
+        let dir = create_private_temp_dir("fonda-test-logs").unwrap();
+        let log = dir.join("project.log");
+        let rotated = |index: usize| dir.join(format!("project.log.{}", index));
+
+        std::fs::write(&log, "small").unwrap();
+        rotate_log(&log).unwrap();
+        assert!(log.exists() && !rotated(1).exists());
+
+        std::fs::write(&log, vec![b'x'; LOG_MAX_BYTES as usize]).unwrap();
+        for index in 1..=LOG_KEEP {
+            std::fs::write(rotated(index), index.to_string()).unwrap();
+        }
+        rotate_log(&log).unwrap();
+
+        assert!(!log.exists());
+        assert_eq!(std::fs::metadata(rotated(1)).unwrap().len(), LOG_MAX_BYTES);
+        assert_eq!(std::fs::read_to_string(rotated(2)).unwrap(), "1");
+        assert_eq!(std::fs::read_to_string(rotated(3)).unwrap(), "2");
+        assert!(!rotated(LOG_KEEP + 1).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}